@@ -0,0 +1,20 @@
+use std::{env, fs, path::Path};
+
+fn main() {
+    let out_dir = env::var("OUT_DIR").unwrap();
+
+    println!("cargo:rerun-if-changed=cli-spinners/spinners.json");
+    println!("cargo:rerun-if-changed=assets/fallback_spinners.json");
+
+    let submodule = Path::new("cli-spinners/spinners.json");
+    let source = if submodule.exists() {
+        submodule
+    } else {
+        println!(
+            "cargo:warning=cli-spinners submodule is not checked out, bundling the fallback spinners (run `git submodule update --init`)"
+        );
+        Path::new("assets/fallback_spinners.json")
+    };
+
+    fs::copy(source, Path::new(&out_dir).join("spinners.json")).unwrap();
+}
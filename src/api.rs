@@ -0,0 +1,229 @@
+// Requests to the API which don't stream or show progress themselves, all of which report the
+// API's errors the same way
+
+use serde_json::Value;
+
+use crate::{config::OpenAIConfig, conversation::Message};
+
+// The message of an error response, such as {"error": {"message": "Invalid API key"}}
+fn error_message(res: &Value) -> Option<&str> {
+    res["error"]["message"].as_str()
+}
+
+// Sends the request and returns the response body, such as audio, or the API's error
+pub async fn send_bytes(
+    request: reqwest::RequestBuilder,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let res = request.send().await?;
+    let status = res.status();
+    let body = res.bytes().await?.to_vec();
+
+    if !status.is_success() {
+        let error = serde_json::from_slice::<Value>(&body).unwrap_or_default();
+        // Proxies and gateways answer errors with HTML or nothing at all
+        return Err(error_message(&error)
+            .map_or_else(|| format!("The server answered {}", status), str::to_string)
+            .into());
+    }
+    Ok(body)
+}
+
+// Sends the request and returns the JSON response, or the API's error
+pub async fn send_json(
+    request: reqwest::RequestBuilder,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    let body = send_bytes(request).await?;
+    let json = serde_json::from_slice::<Value>(&body)
+        .map_err(|e| format!("The response is not JSON: {}", e))?;
+
+    // Some servers send errors with a success status
+    if let Some(message) = error_message(&json) {
+        return Err(message.into());
+    }
+    Ok(json)
+}
+
+pub async fn post_json(
+    client: &reqwest::Client,
+    headers: &reqwest::header::HeaderMap,
+    openai: &OpenAIConfig,
+    endpoint: &str,
+    body: &Value,
+) -> Result<Value, Box<dyn std::error::Error>> {
+    send_json(
+        client
+            .post(openai.url(endpoint))
+            .headers(headers.clone())
+            .json(body),
+    )
+    .await
+}
+
+// A chat completion outside the conversation, which returns the answer's text
+pub async fn chat(
+    client: &reqwest::Client,
+    headers: &reqwest::header::HeaderMap,
+    openai: &OpenAIConfig,
+    messages: &[Message],
+    temperature: f64,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let body = serde_json::json!({
+        "model": openai.model,
+        "messages": messages,
+        "temperature": temperature,
+    });
+    let res = post_json(client, headers, openai, "chat/completions", &body).await?;
+
+    Ok(res["choices"][0]["message"]["content"]
+        .as_str()
+        .ok_or("The response holds no answer")?
+        .trim()
+        .to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{headers, serve_once};
+
+    async fn error_of(status: &str, content_type: &str, response: &[u8]) -> String {
+        let (openai, server) = serve_once(status, content_type, response).await;
+        let result = post_json(
+            &reqwest::Client::new(),
+            &headers(),
+            &openai,
+            "embeddings",
+            &serde_json::json!({}),
+        )
+        .await;
+        server.await.unwrap();
+        result.err().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn post_json_sends_the_body_and_returns_the_response() {
+        let (openai, server) = serve_once("200 OK", "application/json", br#"{"data": [1]}"#).await;
+
+        let res = post_json(
+            &reqwest::Client::new(),
+            &headers(),
+            &openai,
+            "embeddings",
+            &serde_json::json!({"input": "text"}),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(res["data"][0], 1);
+        let request = server.await.unwrap();
+        assert!(request.head.starts_with("POST /v1/embeddings HTTP/1.1"));
+        assert!(request.head.contains("authorization: Bearer test-key"));
+        assert_eq!(request.json(), serde_json::json!({"input": "text"}));
+    }
+
+    #[tokio::test]
+    async fn api_errors_are_returned_whatever_the_status() {
+        let error = br#"{"error": {"message": "Invalid API key"}}"#;
+
+        assert_eq!(
+            error_of("401 Unauthorized", "application/json", error).await,
+            "Invalid API key"
+        );
+        assert_eq!(
+            error_of("200 OK", "application/json", error).await,
+            "Invalid API key"
+        );
+    }
+
+    #[tokio::test]
+    async fn errors_without_a_message_give_the_status() {
+        assert_eq!(
+            error_of("502 Bad Gateway", "text/html", b"<html>Bad Gateway</html>").await,
+            "The server answered 502 Bad Gateway"
+        );
+        assert!(error_of("200 OK", "text/html", b"<html></html>")
+            .await
+            .starts_with("The response is not JSON"));
+    }
+
+    #[tokio::test]
+    async fn send_bytes_returns_the_body_as_it_is() {
+        let (openai, server) = serve_once("200 OK", "audio/mpeg", b"\xff\xfb\x90\x00").await;
+
+        let body = send_bytes(reqwest::Client::new().get(openai.url("audio")))
+            .await
+            .unwrap();
+
+        server.await.unwrap();
+        assert_eq!(body, b"\xff\xfb\x90\x00");
+    }
+
+    #[tokio::test]
+    async fn send_bytes_returns_the_api_error() {
+        let (openai, server) = serve_once(
+            "400 Bad Request",
+            "application/json",
+            br#"{"error": {"message": "Invalid voice"}}"#,
+        )
+        .await;
+
+        let error = send_bytes(reqwest::Client::new().get(openai.url("audio")))
+            .await
+            .unwrap_err();
+
+        server.await.unwrap();
+        assert_eq!(error.to_string(), "Invalid voice");
+    }
+
+    #[tokio::test]
+    async fn chat_returns_the_trimmed_answer() {
+        let (openai, server) = serve_once(
+            "200 OK",
+            "application/json",
+            br#"{"choices": [{"message": {"role": "assistant", "content": "\n answer \n"}}]}"#,
+        )
+        .await;
+
+        let answer = chat(
+            &reqwest::Client::new(),
+            &headers(),
+            &openai,
+            &[Message::system("system"), Message::user("question")],
+            0.2,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(answer, "answer");
+        let request = server.await.unwrap();
+        assert!(request
+            .head
+            .starts_with("POST /v1/chat/completions HTTP/1.1"));
+        let body = request.json();
+        assert_eq!(body["model"], openai.model);
+        assert_eq!(body["temperature"], 0.2);
+        assert_eq!(body["messages"][0]["role"], "system");
+        assert_eq!(body["messages"][1]["content"], "question");
+    }
+
+    #[tokio::test]
+    async fn chat_needs_an_answer() {
+        let (openai, server) =
+            serve_once("200 OK", "application/json", br#"{"choices": []}"#).await;
+
+        let result = chat(
+            &reqwest::Client::new(),
+            &headers(),
+            &openai,
+            &[Message::user("question")],
+            0.2,
+        )
+        .await;
+
+        server.await.unwrap();
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "The response holds no answer"
+        );
+    }
+}
@@ -0,0 +1,337 @@
+use std::path::{Path, PathBuf};
+
+use crate::{
+    api,
+    config::{AudioConfig, OpenAIConfig},
+    image,
+};
+
+// The largest file the transcription endpoint accepts
+const MAX_AUDIO_SIZE: usize = 25 * 1024 * 1024;
+
+const AUDIO_TYPES: &[(&str, &str)] = &[
+    ("wav", "audio/wav"),
+    ("mp3", "audio/mpeg"),
+    ("mpga", "audio/mpeg"),
+    ("mpeg", "audio/mpeg"),
+    ("m4a", "audio/mp4"),
+    ("mp4", "audio/mp4"),
+    ("ogg", "audio/ogg"),
+    ("flac", "audio/flac"),
+    ("webm", "audio/webm"),
+];
+
+fn audio_type(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    AUDIO_TYPES
+        .iter()
+        .find(|(name, _)| *name == extension)
+        .map(|(_, mime_type)| *mime_type)
+}
+
+pub async fn transcribe(
+    client: &reqwest::Client,
+    headers: &reqwest::header::HeaderMap,
+    openai: &OpenAIConfig,
+    config: &AudioConfig,
+    path: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let file_path = Path::new(path);
+    let mime_type = audio_type(file_path).ok_or_else(|| {
+        format!(
+            "{} is not an audio file ({})",
+            path,
+            AUDIO_TYPES
+                .iter()
+                .map(|(extension, _)| *extension)
+                .collect::<Vec<&str>>()
+                .join(", ")
+        )
+    })?;
+
+    let data = tokio::fs::read(path)
+        .await
+        .map_err(|e| format!("Error reading {}: {}", path, e))?;
+    if data.len() > MAX_AUDIO_SIZE {
+        return Err(format!(
+            "{} is {:.1} MB, audio files can be at most {} MB",
+            path,
+            data.len() as f64 / 1024.0 / 1024.0,
+            MAX_AUDIO_SIZE / 1024 / 1024
+        )
+        .into());
+    }
+
+    let file_name = file_path.file_name().map_or("audio".to_string(), |name| {
+        name.to_string_lossy().to_string()
+    });
+    let mut form =
+        reqwest::multipart::Form::new().text("model", config.transcription_model.clone());
+    if let Some(language) = &config.language {
+        form = form.text("language", language.clone());
+    }
+    form = form.part(
+        "file",
+        reqwest::multipart::Part::bytes(data)
+            .file_name(file_name)
+            .mime_str(mime_type)?,
+    );
+
+    // The form sets its own Content-Type, with the boundary
+    let mut headers = headers.clone();
+    headers.remove(reqwest::header::CONTENT_TYPE);
+
+    let res = api::send_json(
+        client
+            .post(openai.url("audio/transcriptions"))
+            .headers(headers)
+            .multipart(form),
+    )
+    .await?;
+
+    Ok(res["text"]
+        .as_str()
+        .ok_or("The response holds no transcript")?
+        .trim()
+        .to_string())
+}
+
+// Returns the audio in the configured speech_format
+pub async fn speak(
+    client: &reqwest::Client,
+    headers: &reqwest::header::HeaderMap,
+    openai: &OpenAIConfig,
+    config: &AudioConfig,
+    text: &str,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let body = serde_json::json!({
+        "model": config.speech_model,
+        "input": text,
+        "voice": config.voice,
+        "response_format": config.speech_format,
+    });
+
+    api::send_bytes(
+        client
+            .post(openai.url("audio/speech"))
+            .headers(headers.clone())
+            .json(&body),
+    )
+    .await
+}
+
+pub async fn save_speech(
+    directory: &Path,
+    config: &AudioConfig,
+    text: &str,
+    data: &[u8],
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    tokio::fs::create_dir_all(directory).await?;
+
+    let path = directory.join(format!(
+        "{}-{}.{}",
+        chrono::Local::now().format("%Y-%m-%d_%H-%M-%S"),
+        image::file_name_slug(text),
+        config.speech_format
+    ));
+    tokio::fs::write(&path, data).await?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{headers, serve_once, temp_dir, TempDir};
+
+    // The file's path, which lasts as long as the directory
+    async fn audio_file(name: &str, data: &[u8]) -> (TempDir, PathBuf) {
+        let directory = temp_dir();
+        let path = directory.path().join(name);
+        tokio::fs::write(&path, data).await.unwrap();
+        (directory, path)
+    }
+
+    #[tokio::test]
+    async fn transcribe_posts_the_file_and_returns_the_text() {
+        let (openai, request) = serve_once(
+            "200 OK",
+            "application/json",
+            br#"{"text":" Hello there. \n"}"#,
+        )
+        .await;
+        let config = AudioConfig {
+            language: Some("en".to_string()),
+            ..AudioConfig::default()
+        };
+        let (_directory, path) = audio_file("clip.m4a", b"\x00\x01AUDIO").await;
+
+        let text = transcribe(
+            &reqwest::Client::new(),
+            &headers(),
+            &openai,
+            &config,
+            path.to_str().unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(text, "Hello there.");
+
+        let request = request.await.unwrap();
+        assert!(request.head.starts_with("POST /v1/audio/transcriptions "));
+        assert!(request.head.contains("authorization: Bearer test-key"));
+        assert_eq!(request.head.matches("content-type:").count(), 1);
+
+        let content_type = request
+            .head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-type: "))
+            .unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/form-data; boundary=")
+            .unwrap();
+        let body = request.body_text();
+        assert!(body.starts_with(&format!("--{}\r\n", boundary)));
+        assert!(body.ends_with(&format!("\r\n--{}--\r\n", boundary)));
+        assert!(body.contains("name=\"model\"\r\n\r\nwhisper-1\r\n"));
+        assert!(body.contains("name=\"language\"\r\n\r\nen\r\n"));
+        assert!(body
+            .contains("filename=\"clip.m4a\"\r\nContent-Type: audio/mp4\r\n\r\n\x00\x01AUDIO\r\n"));
+    }
+
+    #[tokio::test]
+    async fn transcribe_leaves_out_an_unset_language() {
+        let (openai, request) = serve_once("200 OK", "application/json", br#"{"text":"hi"}"#).await;
+        let (_directory, path) = audio_file("clip.wav", b"RIFF").await;
+
+        transcribe(
+            &reqwest::Client::new(),
+            &headers(),
+            &openai,
+            &AudioConfig::default(),
+            path.to_str().unwrap(),
+        )
+        .await
+        .unwrap();
+        assert!(!request.await.unwrap().body_text().contains("language"));
+    }
+
+    #[tokio::test]
+    async fn transcribe_returns_the_api_error() {
+        let (openai, _request) = serve_once(
+            "400 Bad Request",
+            "application/json",
+            br#"{"error":{"message":"Invalid file format."}}"#,
+        )
+        .await;
+        let (_directory, path) = audio_file("clip.mp3", b"ID3").await;
+
+        let error = transcribe(
+            &reqwest::Client::new(),
+            &headers(),
+            &openai,
+            &AudioConfig::default(),
+            path.to_str().unwrap(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.to_string(), "Invalid file format.");
+    }
+
+    #[tokio::test]
+    async fn transcribe_needs_a_text_field() {
+        let (openai, _request) = serve_once("200 OK", "application/json", b"{}").await;
+        let (_directory, path) = audio_file("clip.mp3", b"ID3").await;
+
+        let error = transcribe(
+            &reqwest::Client::new(),
+            &headers(),
+            &openai,
+            &AudioConfig::default(),
+            path.to_str().unwrap(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.to_string(), "The response holds no transcript");
+    }
+
+    #[tokio::test]
+    async fn transcribe_rejects_other_files_before_sending() {
+        let (_directory, path) = audio_file("notes.txt", b"text").await;
+
+        let error = transcribe(
+            &reqwest::Client::new(),
+            &headers(),
+            &OpenAIConfig::default(),
+            &AudioConfig::default(),
+            path.to_str().unwrap(),
+        )
+        .await
+        .unwrap_err();
+        assert!(error.to_string().contains("is not an audio file"));
+    }
+
+    #[tokio::test]
+    async fn speak_returns_the_audio() {
+        let audio = b"\xff\xfb\x90\x00frames";
+        let (openai, request) = serve_once("200 OK", "audio/mpeg", audio).await;
+        let config = AudioConfig {
+            voice: "nova".to_string(),
+            ..AudioConfig::default()
+        };
+
+        let data = speak(&reqwest::Client::new(), &headers(), &openai, &config, "Hi")
+            .await
+            .unwrap();
+        assert_eq!(data, audio);
+
+        let request = request.await.unwrap();
+        assert!(request.head.starts_with("POST /v1/audio/speech "));
+        assert_eq!(
+            request.json(),
+            serde_json::json!({
+                "model": "tts-1",
+                "input": "Hi",
+                "voice": "nova",
+                "response_format": "mp3",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn speak_returns_the_api_error() {
+        let (openai, _request) = serve_once(
+            "400 Bad Request",
+            "application/json",
+            br#"{"error":{"message":"Invalid voice bad"}}"#,
+        )
+        .await;
+
+        let error = speak(
+            &reqwest::Client::new(),
+            &headers(),
+            &openai,
+            &AudioConfig::default(),
+            "Hi",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.to_string(), "Invalid voice bad");
+    }
+
+    #[tokio::test]
+    async fn speak_reports_errors_without_a_message() {
+        let (openai, _request) =
+            serve_once("502 Bad Gateway", "text/html", b"<html>upstream</html>").await;
+
+        let error = speak(
+            &reqwest::Client::new(),
+            &headers(),
+            &openai,
+            &AudioConfig::default(),
+            "Hi",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.to_string(), "The server answered 502 Bad Gateway");
+    }
+}
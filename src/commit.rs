@@ -0,0 +1,347 @@
+use std::io::IsTerminal;
+
+use crossterm::style::Stylize;
+
+use crate::{
+    api,
+    config::{CommitConfig, Config},
+    conversation::Message,
+    editor,
+    interrupt::Interrupt,
+    progress::Progress,
+};
+
+const CONVENTIONAL_STYLE: &str = "Follow the Conventional Commits format: a subject line `type(scope): summary`, where type is one of feat, fix, docs, style, refactor, perf, test, build, ci or chore and the scope is optional. Write the summary in the imperative mood, in lowercase and without a trailing period, and keep the subject line under 72 characters. Add a body after a blank line only when the change needs explaining, saying what changed and why, wrapped at 72 characters. Mark breaking changes with a `BREAKING CHANGE:` footer.";
+const PLAIN_STYLE: &str = "Write a capitalized subject line in the imperative mood, without a trailing period and under 72 characters. Add a body after a blank line only when the change needs explaining, saying what changed and why, wrapped at 72 characters.";
+
+// Generated files whose diffs say little and take up a lot of room
+const LOCK_FILES: &[&str] = &[
+    "Cargo.lock",
+    "package-lock.json",
+    "yarn.lock",
+    "pnpm-lock.yaml",
+    "poetry.lock",
+    "Gemfile.lock",
+    "composer.lock",
+    "go.sum",
+];
+
+// Recent subjects are sent along, so messages match the ones already in the history
+const RECENT_COMMITS: usize = 10;
+
+// The output of a git command, or its error
+async fn git(args: &[&str]) -> Result<String, Box<dyn std::error::Error>> {
+    let output = tokio::process::Command::new("git")
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| format!("Error running git: {}", e))?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+fn style_instructions(config: &CommitConfig) -> &str {
+    match config.style.as_str() {
+        "conventional" => CONVENTIONAL_STYLE,
+        "plain" => PLAIN_STYLE,
+        style => style,
+    }
+}
+
+// The diff of each file, starting at its diff --git line
+fn file_diffs(diff: &str) -> Vec<&str> {
+    let mut starts = diff
+        .match_indices("diff --git ")
+        .filter(|(i, _)| *i == 0 || diff.as_bytes()[i - 1] == b'\n')
+        .map(|(i, _)| i)
+        .collect::<Vec<usize>>();
+    if starts.first() != Some(&0) {
+        starts.insert(0, 0);
+    }
+    starts.push(diff.len());
+
+    starts
+        .windows(2)
+        .map(|range| &diff[range[0]..range[1]])
+        .filter(|file| !file.is_empty())
+        .collect()
+}
+
+// The first lines of a file's diff which fit in max_chars, saying how many were left out
+fn cut_diff(file: &str, max_chars: usize) -> String {
+    let lines = file.lines().collect::<Vec<&str>>();
+    let mut cut = String::new();
+    let mut kept = 0;
+    for line in &lines {
+        if cut.chars().count() + line.chars().count() + 1 > max_chars && kept > 0 {
+            break;
+        }
+        cut.push_str(line);
+        cut.push('\n');
+        kept += 1;
+    }
+
+    if kept < lines.len() {
+        cut.push_str(&format!(
+            "[... {} more lines left out]\n",
+            lines.len() - kept
+        ));
+    }
+    cut
+}
+
+// Cuts the diff down to about max_chars: lock files are left out, and the files which don't
+// fit an even share are shortened, so every file keeps at least its first lines
+fn fit_diff(diff: &str, max_chars: usize) -> String {
+    if diff.chars().count() <= max_chars {
+        return diff.to_string();
+    }
+
+    let files = file_diffs(diff)
+        .into_iter()
+        .map(|file| {
+            let header = file.lines().next().unwrap_or("");
+            if LOCK_FILES
+                .iter()
+                .any(|lock_file| header.ends_with(&format!("/{}", lock_file)))
+            {
+                format!("{}\n[lock file changes left out]\n", header)
+            } else {
+                file.to_string()
+            }
+        })
+        .collect::<Vec<String>>();
+
+    // Smallest first, each gets the whole of what it needs up to an even share of what's left
+    let mut order = (0..files.len()).collect::<Vec<usize>>();
+    order.sort_by_key(|i| files[*i].chars().count());
+    let mut limits = vec![0; files.len()];
+    let mut left = max_chars;
+    for (n, i) in order.iter().enumerate() {
+        let share = left / (files.len() - n);
+        limits[*i] = files[*i].chars().count().min(share);
+        left -= limits[*i];
+    }
+
+    files
+        .iter()
+        .zip(limits)
+        .map(|(file, limit)| cut_diff(file, limit))
+        .collect()
+}
+
+fn request_prompt(config: &Config, diff: &str, stat: &str, recent: &str, notes: &str) -> String {
+    let mut prompt = String::new();
+    if !recent.trim().is_empty() {
+        prompt.push_str(&format!(
+            "Recent commit subjects in this repository:\n{}\n",
+            recent.trim_end()
+        ));
+    }
+    if !notes.is_empty() {
+        prompt.push_str(&format!("Notes from the author: {}\n\n", notes));
+    }
+    prompt.push_str(&format!(
+        "Files changed:\n{}\nStaged diff:\n{}",
+        stat.trim_end(),
+        fit_diff(diff, config.commit.max_diff_chars)
+    ));
+    prompt
+}
+
+async fn request_message(
+    client: &reqwest::Client,
+    headers: &reqwest::header::HeaderMap,
+    config: &Config,
+    prompt: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let system = format!(
+        "You write git commit messages for staged changes. {}\nReply with the commit message alone, without quotes or code fences.",
+        style_instructions(&config.commit)
+    );
+    let answer = api::chat(
+        client,
+        headers,
+        &config.openai,
+        &[Message::system(&system), Message::user(prompt)],
+        config.openai.temperature,
+    )
+    .await?;
+    // In case the message was fenced anyway
+    let message = answer
+        .strip_prefix("```")
+        .and_then(|message| message.strip_suffix("```"))
+        .map_or(answer.as_str(), |message| {
+            message.split_once('\n').map_or(message, |(_, rest)| rest)
+        });
+
+    Ok(message.trim().to_string())
+}
+
+// gptcli commit [notes]: writes a message for the staged changes, and commits once accepted
+pub async fn commit_command(
+    client: &reqwest::Client,
+    headers: &reqwest::header::HeaderMap,
+    config: &Config,
+    progress: &Progress,
+    interrupt: &Interrupt,
+    args: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Outside a repository git diff would compare files instead, and print its usage
+    git(&["rev-parse", "--is-inside-work-tree"]).await?;
+    let diff = git(&["diff", "--staged", "--no-color", "--no-ext-diff"]).await?;
+    if diff.trim().is_empty() {
+        return Err("Nothing is staged, stage the changes to commit with git add first".into());
+    }
+    let stat = git(&["diff", "--staged", "--no-color", "--stat"]).await?;
+    // A new repository has no commits yet
+    let recent = git(&["log", &format!("-{}", RECENT_COMMITS), "--format=%s"])
+        .await
+        .unwrap_or_default();
+    let prompt = request_prompt(config, &diff, &stat, &recent, &args.join(" "));
+
+    loop {
+        let request = request_message(client, headers, config, &prompt);
+        let Some(message) = progress.run(interrupt, request).await else {
+            println!("Request cancelled, nothing was committed");
+            return Ok(());
+        };
+        let mut message = message?;
+
+        println!("\n{}\n", message.clone().bold());
+        // Without a terminal to confirm in, the message is only shown
+        if !std::io::stdin().is_terminal() {
+            return Ok(());
+        }
+
+        loop {
+            let choice = dialoguer::Select::new()
+                .items(&["Commit", "Edit", "Regenerate", "Cancel"])
+                .default(0)
+                .interact_opt()?;
+
+            match choice {
+                Some(0) => {
+                    let status = tokio::process::Command::new("git")
+                        .args(["commit", "-m", &message])
+                        .status()
+                        .await?;
+                    if !status.success() {
+                        println!(
+                            "git commit failed ({}), the message was:\n\n{}",
+                            status, message
+                        );
+                    }
+                    return Ok(());
+                }
+                Some(1) => {
+                    message = editor::edit(&message).await?;
+                    if message.is_empty() {
+                        println!("The message is empty, nothing was committed");
+                        return Ok(());
+                    }
+                    println!("\n{}\n", message.clone().bold());
+                }
+                Some(2) => break,
+                _ => {
+                    println!("Nothing was committed");
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_diff(path: &str, lines: usize) -> String {
+        let mut diff = format!(
+            "diff --git a/{0} b/{0}\n--- a/{0}\n+++ b/{0}\n@@ -1,{1} +1,{1} @@\n",
+            path, lines
+        );
+        for n in 0..lines {
+            diff.push_str(&format!("+line {}\n", n));
+        }
+        diff
+    }
+
+    #[test]
+    fn cut_diff_keeps_the_first_lines_that_fit() {
+        let file = "one\ntwo\nthree\nfour\n";
+
+        assert_eq!(cut_diff(file, 100), file);
+        assert_eq!(cut_diff(file, 8), "one\ntwo\n[... 2 more lines left out]\n");
+        // The first line is kept even when it doesn't fit
+        assert_eq!(cut_diff(file, 0), "one\n[... 3 more lines left out]\n");
+    }
+
+    #[test]
+    fn file_diffs_split_at_each_file() {
+        let diff = format!("{}{}", file_diff("a.rs", 1), file_diff("b.rs", 2));
+
+        let files = file_diffs(&diff);
+        assert_eq!(files, [file_diff("a.rs", 1), file_diff("b.rs", 2)]);
+    }
+
+    #[test]
+    fn fit_diff_leaves_small_diffs_alone() {
+        let diff = format!("{}{}", file_diff("Cargo.lock", 5), file_diff("a.rs", 5));
+
+        assert_eq!(fit_diff(&diff, diff.len()), diff);
+    }
+
+    #[test]
+    fn fit_diff_leaves_out_lock_files() {
+        let diff = format!(
+            "{}{}",
+            file_diff("Cargo.lock", 200),
+            file_diff("src/a.rs", 5)
+        );
+
+        assert_eq!(
+            fit_diff(&diff, 1000),
+            format!(
+                "diff --git a/Cargo.lock b/Cargo.lock\n[lock file changes left out]\n{}",
+                file_diff("src/a.rs", 5)
+            )
+        );
+    }
+
+    #[test]
+    fn fit_diff_gives_what_small_files_leave_to_large_ones() {
+        let small = file_diff("small.rs", 2);
+        let large = file_diff("large.rs", 300);
+        let diff = format!("{}{}", large, small);
+        let max_chars = 1000;
+
+        let fitted = fit_diff(&diff, max_chars);
+
+        // The small file is whole, and the files keep their order
+        assert!(fitted.starts_with("diff --git a/large.rs"));
+        assert!(fitted.ends_with(&small));
+        assert!(fitted.contains("more lines left out]"));
+        let large_part = &fitted[..fitted.len() - small.len()];
+        // The large file gets the rest, give or take the note about the lines left out
+        assert!(large_part.len() > max_chars - small.len() - 40);
+        assert!(large_part.len() <= max_chars - small.len() + 40);
+    }
+
+    #[test]
+    fn fit_diff_keeps_the_first_lines_of_every_file() {
+        let diff = (0..10)
+            .map(|n| file_diff(&format!("{}.rs", n), 100))
+            .collect::<String>();
+
+        let fitted = fit_diff(&diff, 500);
+
+        for n in 0..10 {
+            assert!(fitted.contains(&format!("diff --git a/{0}.rs b/{0}.rs\n", n)));
+        }
+        assert_eq!(fitted.matches("more lines left out]").count(), 10);
+    }
+}
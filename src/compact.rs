@@ -0,0 +1,102 @@
+use crossterm::style::Stylize;
+
+use crate::{
+    api,
+    config::Config,
+    conversation::{Conversation, Message},
+    interrupt::Interrupt,
+    progress::Progress,
+};
+
+const SUMMARY_PROMPT: &str = "Summarize the conversation below, so that it can be continued from the summary alone. Keep the decisions made, facts, names, file paths, commands, code and errors that still matter, and the open questions. Leave out pleasantries. Be concise, and write it as notes rather than a narrative.";
+
+fn transcript(messages: &[&Message]) -> String {
+    messages
+        .iter()
+        .map(|message| {
+            let label = match message.role.as_str() {
+                "user" => "User",
+                "assistant" => "Assistant",
+                _ => "Context",
+            };
+            format!("[{}]\n{}", label, message.content.text())
+        })
+        .collect::<Vec<String>>()
+        .join("\n\n")
+}
+
+async fn summarize(
+    client: &reqwest::Client,
+    headers: &reqwest::header::HeaderMap,
+    config: &Config,
+    messages: &[&Message],
+) -> Result<String, Box<dyn std::error::Error>> {
+    api::chat(
+        client,
+        headers,
+        &config.openai,
+        &[
+            Message::system(SUMMARY_PROMPT),
+            Message::user(&transcript(messages)),
+        ],
+        0.2,
+    )
+    .await
+}
+
+// Summarizes all but the last compact_keep messages once the conversation is over
+// compact_threshold, or always for /compact. Returns whether it was compacted
+pub async fn compact(
+    client: &reqwest::Client,
+    headers: &reqwest::header::HeaderMap,
+    config: &Config,
+    conversation: &mut Conversation,
+    progress: &Progress,
+    interrupt: &Interrupt,
+    force: bool,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let before = conversation.estimated_tokens();
+    if !force && (config.app.compact_threshold == 0 || before <= config.app.compact_threshold) {
+        return Ok(false);
+    }
+
+    let messages = conversation.compactable(config.app.compact_keep);
+    if messages.is_empty() {
+        if force {
+            println!(
+                "Nothing to compact, only the last {} messages are left (app.compact_keep)",
+                config.app.compact_keep.max(1)
+            );
+        }
+        return Ok(false);
+    }
+    let count = messages.len();
+
+    println!(
+        "{}",
+        format!(
+            "Compacting {} older messages (about {} tokens in the conversation)...",
+            count, before
+        )
+        .dark_grey()
+    );
+    let summary = progress
+        .run(interrupt, summarize(client, headers, config, &messages))
+        .await;
+    let Some(summary) = summary else {
+        println!("Compaction cancelled, the conversation is unchanged");
+        return Ok(false);
+    };
+
+    conversation.compact(config.app.compact_keep, &summary?);
+    println!(
+        "{}",
+        format!(
+            "Replaced {} messages with a summary, about {} tokens are left. The full conversation stays in /branches and the log",
+            count,
+            conversation.estimated_tokens()
+        )
+        .dark_grey()
+    );
+    Ok(true)
+}
@@ -1,64 +1,176 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 
+// Bump this and add a migration to MIGRATIONS whenever a key is renamed or changes meaning
+pub const CONFIG_VERSION: u32 = 1;
+
 #[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
 pub struct Config {
+    pub version: u32,
     pub openai: OpenAIConfig,
     pub app: AppConfig,
+    pub network: NetworkConfig,
+    pub images: ImagesConfig,
+    pub audio: AudioConfig,
+    pub embeddings: EmbeddingsConfig,
+    pub rag: RagConfig,
+    pub commit: CommitConfig,
 }
 
 #[serde_as]
 #[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
 pub struct OpenAIConfig {
     pub model: String,
-    pub temperature: f32,
-    pub top_p: f32,
+    pub temperature: f64,
+    pub top_p: f64,
     pub n: u32,
     pub stop: Option<String>,
     #[serde_as(as = "serde_with::DisplayFromStr")]
     pub max_tokens: f32,
-    pub frequency_penalty: f32,
-    pub presence_penalty: f32,
+    pub frequency_penalty: f64,
+    pub presence_penalty: f64,
     pub logit_bias: Option<String>,
     pub user: Option<String>,
+    pub api_key_command: Option<String>,
+    // Another OpenAI compatible server, such as a proxy or a local mock
+    pub base_url: String,
+}
+
+impl OpenAIConfig {
+    pub fn url(&self, endpoint: &str) -> String {
+        format!("{}/{}", self.base_url.trim_end_matches('/'), endpoint)
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
 pub struct AppConfig {
     pub prompt: String,
-    pub rainbow_speed: f32,
+    pub rainbow_speed: f64,
     pub notify_save: bool,
     pub response_prefix: String,
     pub rainbow_delay: u64,
     pub save_conversation: bool,
     pub syntax_theme: String,
+    pub spinner: String,
+    pub spinner_color: String,
+    pub progress_style: String,
+    pub history_size: usize,
+    pub history_ignore_space: bool,
+    pub inline_images: String,
+    // Estimated tokens above which older messages are summarized, 0 never compacts
+    pub compact_threshold: usize,
+    // How many of the last messages compaction leaves as they are
+    pub compact_keep: usize,
 }
 
-pub async fn save_config(
-    config_dir: &str,
-    config: &Config,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let config_path = format!("{}/config.toml", config_dir);
-    let config_file = toml::to_string(&config)?;
-    tokio::fs::write(config_path, config_file).await?;
-    Ok(())
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct NetworkConfig {
+    // Seconds, 0 waits forever
+    pub connect_timeout: f64,
+    pub read_timeout: f64,
+    pub http_proxy: Option<String>,
+    pub https_proxy: Option<String>,
+    pub no_proxy: Option<String>,
+    // PEM files, trusted on top of the system roots
+    pub ca_certificates: Vec<String>,
+    pub client_certificate: Option<String>,
+    pub client_key: Option<String>,
 }
 
-pub async fn create_config(config_dir: &str) -> Result<Config, Box<dyn std::error::Error>> {
-    let config = Config {
-        openai: OpenAIConfig {
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ImagesConfig {
+    pub model: String,
+    pub size: String,
+    pub quality: String,
+    pub n: u32,
+    // Where /imagine saves images, the data directory's images folder when unset
+    pub directory: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AudioConfig {
+    pub transcription_model: String,
+    // An ISO-639-1 code such as "en", detected from the audio when unset
+    pub language: Option<String>,
+    pub speech_model: String,
+    pub voice: String,
+    pub speech_format: String,
+    // Where /speak saves audio, the data directory's audio folder when unset
+    pub directory: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct EmbeddingsConfig {
+    // "api" for the embeddings endpoint, or "local" for a built-in embedder which needs no network
+    pub embedder: String,
+    pub model: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RagConfig {
+    // How many chunks are sent with each message
+    pub top_k: usize,
+    // In characters, used by gptcli index
+    pub chunk_size: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CommitConfig {
+    // "conventional", "plain", or instructions of your own
+    pub style: String,
+    // Larger staged diffs are cut down to about this many characters
+    pub max_diff_chars: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            version: CONFIG_VERSION,
+            openai: OpenAIConfig::default(),
+            app: AppConfig::default(),
+            network: NetworkConfig::default(),
+            images: ImagesConfig::default(),
+            audio: AudioConfig::default(),
+            embeddings: EmbeddingsConfig::default(),
+            rag: RagConfig::default(),
+            commit: CommitConfig::default(),
+        }
+    }
+}
+
+impl Default for OpenAIConfig {
+    fn default() -> Self {
+        OpenAIConfig {
             model: "gpt-3.5-turbo".to_string(),
             temperature: 1.0,
             top_p: 1.0,
             n: 1,
             stop: None,
-            max_tokens: std::f32::INFINITY,
+            max_tokens: f32::INFINITY,
             frequency_penalty: 0.0,
             presence_penalty: 0.0,
             logit_bias: None,
             user: None,
-        },
-        app: AppConfig {
+            api_key_command: None,
+            base_url: "https://api.openai.com/v1".to_string(),
+        }
+    }
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        AppConfig {
             prompt: "Please wrap code in triple backticks, with the language specified. For example, ```python\nprint('Hello world')\n```".to_string(),
             rainbow_speed: 15.0,
             notify_save: true,
@@ -66,8 +178,656 @@ pub async fn create_config(config_dir: &str) -> Result<Config, Box<dyn std::erro
             rainbow_delay: 100,
             save_conversation: false,
             syntax_theme: "base16-ocean.dark".to_string(),
+            spinner: "random".to_string(),
+            spinner_color: "rainbow".to_string(),
+            progress_style: "full".to_string(),
+            history_size: 1000,
+            history_ignore_space: true,
+            inline_images: "auto".to_string(),
+            compact_threshold: 12000,
+            compact_keep: 4,
+        }
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            connect_timeout: 10.0,
+            read_timeout: 300.0,
+            http_proxy: None,
+            https_proxy: None,
+            no_proxy: None,
+            ca_certificates: vec![],
+            client_certificate: None,
+            client_key: None,
+        }
+    }
+}
+
+impl Default for ImagesConfig {
+    fn default() -> Self {
+        ImagesConfig {
+            model: "dall-e-3".to_string(),
+            size: "1024x1024".to_string(),
+            quality: "standard".to_string(),
+            n: 1,
+            directory: None,
+        }
+    }
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        AudioConfig {
+            transcription_model: "whisper-1".to_string(),
+            language: None,
+            speech_model: "tts-1".to_string(),
+            voice: "alloy".to_string(),
+            speech_format: "mp3".to_string(),
+            directory: None,
+        }
+    }
+}
+
+impl Default for EmbeddingsConfig {
+    fn default() -> Self {
+        EmbeddingsConfig {
+            embedder: "api".to_string(),
+            model: "text-embedding-3-small".to_string(),
+        }
+    }
+}
+
+impl Default for RagConfig {
+    fn default() -> Self {
+        RagConfig {
+            top_k: 4,
+            chunk_size: 1500,
+        }
+    }
+}
+
+impl Default for CommitConfig {
+    fn default() -> Self {
+        CommitConfig {
+            style: "conventional".to_string(),
+            max_diff_chars: 24000,
+        }
+    }
+}
+
+// MIGRATIONS[n] upgrades a version n config to version n + 1
+const MIGRATIONS: &[fn(&mut toml::Table)] = &[
+    // Version 0 configs have no version key, the keys added since are filled in with defaults
+    |_| {},
+];
+
+fn migrate(table: &mut toml::Table) -> Option<u32> {
+    let version = match table.get("version") {
+        Some(version) => match version.as_integer().map(u32::try_from) {
+            Some(Ok(version)) => version,
+            _ => {
+                println!("Invalid config version {}, assuming 0", version);
+                0
+            }
         },
+        None => 0,
+    };
+
+    if version > CONFIG_VERSION {
+        println!(
+            "config.toml has version {}, but this gptcli only knows version {}. Some settings may be ignored.",
+            version, CONFIG_VERSION
+        );
+        return None;
+    }
+
+    if version == CONFIG_VERSION {
+        return None;
+    }
+
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(table);
+    }
+    table.insert(
+        "version".to_string(),
+        toml::Value::Integer(CONFIG_VERSION.into()),
+    );
+
+    Some(version)
+}
+
+fn is_valid(probe: toml::Table) -> bool {
+    Config::deserialize(toml::Value::Table(probe)).is_ok()
+}
+
+// Removes keys that don't deserialize on their own, so one bad value doesn't discard the whole config
+fn remove_invalid_keys(table: &mut toml::Table) -> Vec<String> {
+    let mut invalid = vec![];
+
+    for (section, value) in table.iter_mut() {
+        let Some(section_table) = value.as_table_mut() else {
+            continue;
+        };
+
+        let invalid_keys = section_table
+            .iter()
+            .filter(|(key, value)| {
+                let section_probe = toml::Table::from_iter([((*key).clone(), (*value).clone())]);
+                !is_valid(toml::Table::from_iter([(
+                    section.clone(),
+                    toml::Value::Table(section_probe),
+                )]))
+            })
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<String>>();
+
+        for key in invalid_keys {
+            let value = section_table.remove(&key).unwrap();
+            invalid.push(format!("{}.{} = {}", section, key, value));
+        }
+    }
+
+    let invalid_keys = table
+        .iter()
+        .filter(|(key, value)| {
+            !value.is_table()
+                && !is_valid(toml::Table::from_iter([((*key).clone(), (*value).clone())]))
+        })
+        .map(|(key, _)| key.clone())
+        .collect::<Vec<String>>();
+
+    for key in invalid_keys {
+        let value = table.remove(&key).unwrap();
+        invalid.push(format!("{} = {}", key, value));
+    }
+
+    invalid
+}
+
+pub async fn load_config(config_dir: &str) -> Result<Config, Box<dyn std::error::Error>> {
+    let config_path = format!("{}/config.toml", config_dir);
+
+    if !std::path::Path::new(&config_path).exists() {
+        return create_config(config_dir).await;
+    }
+
+    let config_file = tokio::fs::read_to_string(&config_path).await?;
+
+    let mut table = match config_file.parse::<toml::Table>() {
+        Ok(table) => table,
+        Err(e) => {
+            // Not even valid TOML, so there is nothing to salvage
+            println!("Error parsing config: {}", e);
+
+            // Move config.toml to config.toml.bak
+            tokio::fs::rename(&config_path, format!("{}.bak", config_path)).await?;
+            println!("Your old config has been moved to {}.bak", config_path);
+
+            return create_config(config_dir).await;
+        }
+    };
+
+    let migrated_from = migrate(&mut table);
+
+    let invalid = remove_invalid_keys(&mut table);
+    if !invalid.is_empty() {
+        println!("Ignoring invalid config values, the defaults are used instead:");
+        for key in &invalid {
+            println!("  {}", key);
+        }
+    }
+
+    let mut unknown = vec![];
+    let config: Config = serde_ignored::deserialize(toml::Value::Table(table), |path| {
+        unknown.push(path.to_string())
+    })?;
+
+    if !unknown.is_empty() {
+        println!("Ignoring unknown config keys: {}", unknown.join(", "));
+    }
+
+    if let Some(version) = migrated_from {
+        let backup_path = format!("{}.v{}.bak", config_path, version);
+        tokio::fs::write(&backup_path, config_file).await?;
+        save_config(config_dir, &config).await?;
+
+        println!(
+            "Migrated config.toml from version {} to {} (the old file is kept at {})",
+            version, CONFIG_VERSION, backup_path
+        );
+    }
+
+    Ok(config)
+}
+
+// Every "section.key" that can be read with /get and changed with /set
+pub fn keys() -> Vec<String> {
+    // JSON keeps the unset Option fields as nulls, unlike TOML
+    let Ok(serde_json::Value::Object(sections)) = serde_json::to_value(Config::default()) else {
+        return vec![];
     };
+
+    sections
+        .iter()
+        .filter_map(|(section, value)| Some((section, value.as_object()?)))
+        .flat_map(|(section, keys)| keys.keys().map(move |key| format!("{}.{}", section, key)))
+        .collect()
+}
+
+pub fn get_value(config: &Config, key: &str) -> Option<serde_json::Value> {
+    let (section, key) = key.split_once('.')?;
+    serde_json::to_value(config)
+        .ok()?
+        .get(section)?
+        .get(key)
+        .cloned()
+}
+
+pub fn set_json_value(
+    config: &mut Config,
+    key: &str,
+    value: serde_json::Value,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (section, field) = key
+        .split_once('.')
+        .ok_or_else(|| format!("{} is not a config key", key))?;
+
+    let mut json = serde_json::to_value(&*config)?;
+    match json
+        .get_mut(section)
+        .and_then(|section| section.get_mut(field))
+    {
+        Some(old_value) => *old_value = value,
+        None => return Err(format!("{} is not a config key", key).into()),
+    }
+
+    *config = serde_json::from_value(json)?;
+    Ok(())
+}
+
+// Values are parsed as JSON (numbers, booleans, null to unset), anything else is a string
+pub fn set_value(
+    config: &mut Config,
+    key: &str,
+    value: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let value = value.trim();
+    let string_value = serde_json::Value::String(value.trim_matches('"').to_string());
+
+    match serde_json::from_str::<serde_json::Value>(value) {
+        Ok(json_value) if !json_value.is_string() => {
+            // Numbers are also accepted by string fields like max_tokens
+            set_json_value(config, key, json_value)
+                .or_else(|_| set_json_value(config, key, string_value))
+        }
+        _ => set_json_value(config, key, string_value),
+    }
+}
+
+// Saves the config, with the values changed by /set since the last /save put back to their originals
+pub async fn save_config_except(
+    config_dir: &str,
+    config: &Config,
+    overrides: &HashMap<String, serde_json::Value>,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut json = serde_json::to_value(config)?;
+    for (key, original) in overrides {
+        if let Some((section, field)) = key.split_once('.') {
+            if let Some(value) = json
+                .get_mut(section)
+                .and_then(|section| section.get_mut(field))
+            {
+                *value = original.clone();
+            }
+        }
+    }
+
+    save_config(config_dir, &serde_json::from_value(json)?).await
+}
+
+// The file's table with the config's values put in, keeping the keys this version doesn't know
+// and the file's order. Known keys the config leaves unset are dropped.
+fn merge_config(file: toml::Table, config: toml::Table) -> toml::Table {
+    let known = keys();
+    let mut merged = toml::Table::new();
+
+    for (key, value) in file {
+        let merged_value = match (value, config.get(&key)) {
+            (toml::Value::Table(file_section), Some(toml::Value::Table(config_section))) => {
+                let mut section = toml::Table::new();
+                for (field, value) in file_section {
+                    if let Some(value) = config_section.get(&field) {
+                        section.insert(field, value.clone());
+                    } else if !known.contains(&format!("{}.{}", key, field)) {
+                        section.insert(field, value);
+                    }
+                }
+                for (field, value) in config_section {
+                    if !section.contains_key(field) {
+                        section.insert(field.clone(), value.clone());
+                    }
+                }
+                toml::Value::Table(section)
+            }
+            (_, Some(config_value)) => config_value.clone(),
+            (value, None) => value,
+        };
+        merged.insert(key, merged_value);
+    }
+
+    for (key, value) in config {
+        if !merged.contains_key(&key) {
+            merged.insert(key, value);
+        }
+    }
+
+    merged
+}
+
+// Returns whether config.toml was written, which it isn't when nothing changed or when it
+// belongs to a newer gptcli
+pub async fn save_config(
+    config_dir: &str,
+    config: &Config,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let config_path = format!("{}/config.toml", config_dir);
+
+    let file = match tokio::fs::read_to_string(&config_path).await {
+        Ok(config_file) => config_file.parse::<toml::Table>().unwrap_or_default(),
+        Err(_) => toml::Table::new(),
+    };
+
+    let file_version = file.get("version").and_then(|version| version.as_integer());
+    if file_version.is_some_and(|version| version > CONFIG_VERSION.into()) {
+        println!("config.toml was written by a newer gptcli, so it's left as it is");
+        return Ok(false);
+    }
+
+    let toml::Value::Table(config) = toml::Value::try_from(config)? else {
+        return Err("The config is not a table".into());
+    };
+    let merged = merge_config(file.clone(), config);
+    if merged == file {
+        return Ok(false);
+    }
+
+    tokio::fs::write(config_path, toml::to_string(&merged)?).await?;
+    Ok(true)
+}
+
+pub async fn create_config(config_dir: &str) -> Result<Config, Box<dyn std::error::Error>> {
+    let config = Config::default();
     save_config(config_dir, &config).await?;
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{temp_dir, TempDir};
+
+    fn table(text: &str) -> toml::Table {
+        text.parse().unwrap()
+    }
+
+    // The directory, and its path as the config functions take it
+    async fn config_dir(config_file: Option<&str>) -> (TempDir, String) {
+        let directory = temp_dir();
+        if let Some(config_file) = config_file {
+            tokio::fs::write(directory.path().join("config.toml"), config_file)
+                .await
+                .unwrap();
+        }
+        let path = directory.path().display().to_string();
+        (directory, path)
+    }
+
+    async fn saved(config_dir: &str) -> toml::Table {
+        table(
+            &tokio::fs::read_to_string(format!("{}/config.toml", config_dir))
+                .await
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn migrates_configs_without_a_version() {
+        let mut config = table("[openai]\nmodel = \"gpt-4\"\n");
+
+        assert_eq!(migrate(&mut config), Some(0));
+        assert_eq!(config["version"].as_integer(), Some(CONFIG_VERSION.into()));
+        assert_eq!(config["openai"]["model"].as_str(), Some("gpt-4"));
+    }
+
+    #[test]
+    fn invalid_versions_are_migrated_from_0() {
+        let mut config = table("version = \"one\"\n");
+
+        assert_eq!(migrate(&mut config), Some(0));
+        assert_eq!(config["version"].as_integer(), Some(CONFIG_VERSION.into()));
+    }
+
+    #[test]
+    fn current_and_newer_versions_are_left_alone() {
+        for version in [CONFIG_VERSION, CONFIG_VERSION + 1] {
+            let text = format!("version = {}\n[app]\nfuture = true\n", version);
+            let mut config = table(&text);
+
+            assert_eq!(migrate(&mut config), None);
+            assert_eq!(config, table(&text));
+        }
+    }
+
+    #[test]
+    fn every_version_has_a_migration() {
+        assert_eq!(MIGRATIONS.len(), CONFIG_VERSION as usize);
+    }
+
+    #[test]
+    fn removes_only_the_invalid_keys() {
+        let mut config = table(
+            "version = \"one\"\nunknown = 1\n\
+             [openai]\nmodel = \"gpt-4\"\ntemperature = \"hot\"\nmax_tokens = \"many\"\n\
+             [app]\nhistory_size = -1\nunknown = \"kept\"\n",
+        );
+
+        let mut invalid = remove_invalid_keys(&mut config);
+        invalid.sort();
+
+        assert_eq!(
+            invalid,
+            [
+                "app.history_size = -1",
+                "openai.max_tokens = \"many\"",
+                "openai.temperature = \"hot\"",
+                "version = \"one\"",
+            ]
+        );
+        assert_eq!(
+            config,
+            table("unknown = 1\n[openai]\nmodel = \"gpt-4\"\n[app]\nunknown = \"kept\"\n")
+        );
+    }
+
+    #[test]
+    fn sets_values_by_type() {
+        let mut config = Config::default();
+
+        set_value(&mut config, "openai.temperature", "0.5").unwrap();
+        set_value(&mut config, "app.notify_save", "false").unwrap();
+        set_value(&mut config, "app.history_size", " 20 ").unwrap();
+        set_value(&mut config, "openai.model", "gpt-4").unwrap();
+        set_value(&mut config, "app.prompt", "\"Be brief.\"").unwrap();
+        set_value(&mut config, "openai.stop", "END").unwrap();
+
+        assert_eq!(config.openai.temperature, 0.5);
+        assert!(!config.app.notify_save);
+        assert_eq!(config.app.history_size, 20);
+        assert_eq!(config.openai.model, "gpt-4");
+        assert_eq!(config.app.prompt, "Be brief.");
+        assert_eq!(config.openai.stop.as_deref(), Some("END"));
+    }
+
+    #[test]
+    fn numbers_go_into_string_fields_and_null_unsets() {
+        let mut config = Config::default();
+
+        set_value(&mut config, "openai.max_tokens", "256").unwrap();
+        set_value(&mut config, "openai.model", "4").unwrap();
+        set_value(&mut config, "openai.stop", "END").unwrap();
+        set_value(&mut config, "openai.stop", "null").unwrap();
+
+        assert_eq!(config.openai.max_tokens, 256.0);
+        assert_eq!(config.openai.model, "4");
+        assert_eq!(config.openai.stop, None);
+    }
+
+    #[test]
+    fn json_values_are_restored_as_they_were() {
+        let mut config = Config::default();
+        let path = r"C:\Users\me\client.pem";
+        set_json_value(
+            &mut config,
+            "network.client_certificate",
+            serde_json::Value::String(path.to_string()),
+        )
+        .unwrap();
+        let original = get_value(&config, "network.client_certificate").unwrap();
+
+        set_value(&mut config, "network.client_certificate", "other.pem").unwrap();
+        set_json_value(&mut config, "network.client_certificate", original).unwrap();
+
+        assert_eq!(config.network.client_certificate.as_deref(), Some(path));
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_wrong_types() {
+        let mut config = Config::default();
+
+        for (key, value) in [
+            ("openai", "1"),
+            ("openai.unknown", "1"),
+            ("unknown.model", "1"),
+            ("app.history_size", "many"),
+            ("app.notify_save", "maybe"),
+        ] {
+            assert!(set_value(&mut config, key, value).is_err(), "{}", key);
+        }
+        assert_eq!(config.app.history_size, AppConfig::default().history_size);
+    }
+
+    #[tokio::test]
+    async fn saving_keeps_unknown_keys_and_the_order() {
+        let (_directory, config_dir) = config_dir(Some(
+            "version = 1\nfuture = \"kept\"\n\
+             [app]\nspinner = \"line\"\nfuture_key = 3\n\
+             [future_section]\nkey = true\n\
+             [openai]\nmodel = \"gpt-4\"\nstop = \"END\"\n",
+        ))
+        .await;
+        let mut config = Config::default();
+        config.app.spinner = "dots".to_string();
+        config.openai.model = "gpt-4".to_string();
+
+        assert!(save_config(&config_dir, &config).await.unwrap());
+
+        let saved = saved(&config_dir).await;
+        assert_eq!(saved["future"].as_str(), Some("kept"));
+        assert_eq!(saved["app"]["spinner"].as_str(), Some("dots"));
+        assert_eq!(saved["app"]["future_key"].as_integer(), Some(3));
+        assert_eq!(saved["future_section"]["key"].as_bool(), Some(true));
+        // Unset in the config, so gone from the file
+        assert!(saved["openai"].get("stop").is_none());
+        assert_eq!(
+            saved.keys().take(5).collect::<Vec<_>>(),
+            ["version", "future", "app", "future_section", "openai"]
+        );
+        assert_eq!(
+            saved["app"]
+                .as_table()
+                .unwrap()
+                .keys()
+                .take(2)
+                .collect::<Vec<_>>(),
+            ["spinner", "future_key"]
+        );
+
+        let config: Config = saved.try_into().unwrap();
+        assert_eq!(config.app.history_size, AppConfig::default().history_size);
+    }
+
+    #[tokio::test]
+    async fn unchanged_configs_are_not_rewritten() {
+        let (_directory, config_dir) = config_dir(None).await;
+        assert!(save_config(&config_dir, &Config::default()).await.unwrap());
+
+        // A comment would be lost by a rewrite
+        let path = format!("{}/config.toml", config_dir);
+        let config_file = format!(
+            "# mine\n{}",
+            tokio::fs::read_to_string(&path).await.unwrap()
+        );
+        tokio::fs::write(&path, &config_file).await.unwrap();
+
+        assert!(!save_config(&config_dir, &Config::default()).await.unwrap());
+        assert_eq!(tokio::fs::read_to_string(&path).await.unwrap(), config_file);
+    }
+
+    #[tokio::test]
+    async fn newer_configs_are_not_rewritten() {
+        let config_file = format!(
+            "version = {}\n[app]\nspinner = \"line\"\n",
+            CONFIG_VERSION + 1
+        );
+        let (_directory, config_dir) = config_dir(Some(&config_file)).await;
+
+        assert!(!save_config(&config_dir, &Config::default()).await.unwrap());
+        assert_eq!(
+            tokio::fs::read_to_string(format!("{}/config.toml", config_dir))
+                .await
+                .unwrap(),
+            config_file
+        );
+    }
+
+    #[tokio::test]
+    async fn saving_puts_back_the_overridden_values() {
+        let (_directory, config_dir) = config_dir(None).await;
+        let mut config = Config::default();
+        config.openai.model = "set-for-now".to_string();
+        config.app.spinner = "line".to_string();
+        let overrides = HashMap::from([(
+            "openai.model".to_string(),
+            serde_json::Value::String("gpt-4".to_string()),
+        )]);
+
+        save_config_except(&config_dir, &config, &overrides)
+            .await
+            .unwrap();
+
+        let saved = saved(&config_dir).await;
+        assert_eq!(saved["openai"]["model"].as_str(), Some("gpt-4"));
+        assert_eq!(saved["app"]["spinner"].as_str(), Some("line"));
+    }
+
+    #[tokio::test]
+    async fn loading_migrates_and_keeps_a_backup() {
+        let (_directory, config_dir) =
+            config_dir(Some("[app]\nspinner = \"line\"\nunknown = 1\n")).await;
+
+        let config = load_config(&config_dir).await.unwrap();
+
+        assert_eq!(config.app.spinner, "line");
+        let saved = saved(&config_dir).await;
+        assert_eq!(saved["version"].as_integer(), Some(CONFIG_VERSION.into()));
+        assert_eq!(saved["app"]["unknown"].as_integer(), Some(1));
+        assert_eq!(
+            tokio::fs::read_to_string(format!("{}/config.toml.v0.bak", config_dir))
+                .await
+                .unwrap(),
+            "[app]\nspinner = \"line\"\nunknown = 1\n"
+        );
+    }
+}
@@ -0,0 +1,723 @@
+use std::{fmt, path::Path};
+
+use crossterm::style::Stylize;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Message {
+    pub role: String,
+    pub content: Content,
+}
+
+// Plain text, or the multi-part form used to send images
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Content {
+    Text(String),
+    Parts(Vec<Part>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Part {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImageUrl {
+    // A data: URL with the base64 encoded image
+    pub url: String,
+}
+
+impl Content {
+    // The text parts, without the images
+    pub fn text(&self) -> String {
+        match self {
+            Content::Text(text) => text.clone(),
+            Content::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    Part::Text { text } => Some(text.as_str()),
+                    Part::ImageUrl { .. } => None,
+                })
+                .collect::<Vec<&str>>()
+                .join("\n"),
+        }
+    }
+
+    pub fn images(&self) -> Vec<Part> {
+        match self {
+            Content::Text(_) => vec![],
+            Content::Parts(parts) => parts
+                .iter()
+                .filter(|part| matches!(part, Part::ImageUrl { .. }))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+// The text, with a placeholder for each image
+impl fmt::Display for Content {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text())?;
+        for _ in self.images() {
+            write!(f, "\n[image]")?;
+        }
+        Ok(())
+    }
+}
+
+impl Message {
+    pub fn user(content: &str) -> Self {
+        Message {
+            role: "user".to_string(),
+            content: Content::Text(content.to_string()),
+        }
+    }
+
+    // Text-only messages keep the plain form, which every model accepts
+    pub fn user_with_images(content: &str, images: Vec<Part>) -> Self {
+        if images.is_empty() {
+            return Message::user(content);
+        }
+
+        let mut parts = vec![Part::Text {
+            text: content.to_string(),
+        }];
+        parts.extend(images);
+
+        Message {
+            role: "user".to_string(),
+            content: Content::Parts(parts),
+        }
+    }
+
+    pub fn system(content: &str) -> Self {
+        Message {
+            role: "system".to_string(),
+            content: Content::Text(content.to_string()),
+        }
+    }
+
+    pub fn assistant(content: &str) -> Self {
+        Message {
+            role: "assistant".to_string(),
+            content: Content::Text(content.to_string()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Node {
+    message: Message,
+    parent: Option<usize>,
+    // For a summary made by compaction, the last message it stands in for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compacts: Option<usize>,
+}
+
+// A tree of messages, where /retry and /edit add siblings instead of replacing messages
+#[derive(Serialize)]
+pub struct Conversation {
+    // The root is always the configured prompt
+    nodes: Vec<Node>,
+    // The last message of the current branch, the conversation continues from here
+    head: usize,
+    // Names the session log
+    #[serde(skip)]
+    started: chrono::DateTime<chrono::Local>,
+    // Sent along with the last message, like documents retrieved for it, but not saved
+    #[serde(skip)]
+    context: Option<Message>,
+}
+
+// Session logs hold the whole tree, older ones just the list of messages
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedLog {
+    Tree { nodes: Vec<Node> },
+    Messages(Vec<Message>),
+}
+
+// Every message of a saved session log, from all of its branches, without the prompt
+pub fn saved_messages(json: &str) -> Result<Vec<Message>, Box<dyn std::error::Error>> {
+    Ok(match serde_json::from_str::<SavedLog>(json)? {
+        SavedLog::Tree { nodes } => nodes
+            .into_iter()
+            .filter(|node| node.parent.is_some())
+            .map(|node| node.message)
+            .collect(),
+        SavedLog::Messages(messages) => messages.into_iter().skip(1).collect(),
+    })
+}
+
+fn format_log_entry(message: &Message) -> String {
+    match message.role.as_str() {
+        "user" => format!("[{}]\n{}", "User", message.content),
+        "assistant" => format!("[{}]\n{}", "GPT", message.content),
+        _ => format!("[{}]\n{}", message.role, message.content),
+    }
+}
+
+impl Conversation {
+    pub fn new(prompt: &str) -> Self {
+        Conversation {
+            nodes: vec![Node {
+                message: Message::user(prompt),
+                parent: None,
+                compacts: None,
+            }],
+            head: 0,
+            started: chrono::Local::now(),
+            context: None,
+        }
+    }
+
+    // A new session holding only the current branch
+    pub fn fork(&self) -> Self {
+        let mut conversation = Conversation::new("");
+        conversation.nodes.clear();
+        for message in self.messages() {
+            conversation.nodes.push(Node {
+                message: message.clone(),
+                parent: conversation.nodes.len().checked_sub(1),
+                compacts: None,
+            });
+        }
+        conversation.head = conversation.nodes.len() - 1;
+        conversation
+    }
+
+    // Node ids from the root to the head
+    fn path(&self) -> Vec<usize> {
+        self.path_to(self.head)
+    }
+
+    fn path_to(&self, id: usize) -> Vec<usize> {
+        let mut path = vec![id];
+        while let Some(parent) = self.nodes[*path.last().unwrap()].parent {
+            path.push(parent);
+        }
+        path.reverse();
+        path
+    }
+
+    fn children(&self, id: usize) -> Vec<usize> {
+        (0..self.nodes.len())
+            .filter(|&child| self.nodes[child].parent == Some(id))
+            .collect()
+    }
+
+    // The messages of the current branch
+    pub fn messages(&self) -> Vec<&Message> {
+        self.path()
+            .into_iter()
+            .map(|id| &self.nodes[id].message)
+            .collect()
+    }
+
+    // A rough count, about four characters per token
+    pub fn estimated_tokens(&self) -> usize {
+        self.messages()
+            .iter()
+            .map(|message| message.content.text().chars().count() / 4 + 4)
+            .sum()
+    }
+
+    // Where the messages compaction keeps start in the path: the last `keep` or a few more, so
+    // they start with a question. The last question is always kept, as it's the one being answered
+    fn compaction_end(&self, path: &[usize], keep: usize) -> usize {
+        let mut end = path.len().saturating_sub(keep.max(1)).max(1);
+        while end > 1 && end < path.len() && self.nodes[path[end]].message.role != "user" {
+            end -= 1;
+        }
+        end
+    }
+
+    // The messages compaction would summarize, all but the prompt and the kept ones
+    pub fn compactable(&self, keep: usize) -> Vec<&Message> {
+        let path = self.path();
+        let end = self.compaction_end(&path, keep);
+        path[1..end]
+            .iter()
+            .map(|&id| &self.nodes[id].message)
+            .collect()
+    }
+
+    // Continues in a new branch where the summary replaces the compactable messages. The
+    // original branch stays in the tree, and in the log
+    pub fn compact(&mut self, keep: usize, summary: &str) {
+        let path = self.path();
+        let end = self.compaction_end(&path, keep);
+        if end == 1 {
+            return;
+        }
+
+        self.nodes.push(Node {
+            message: Message::system(&format!(
+                "Summary of the earlier conversation:\n\n{}",
+                summary
+            )),
+            parent: Some(0),
+            compacts: Some(path[end - 1]),
+        });
+        self.head = self.nodes.len() - 1;
+
+        for &id in &path[end..] {
+            let message = self.nodes[id].message.clone();
+            self.nodes.push(Node {
+                message,
+                parent: Some(self.head),
+                compacts: None,
+            });
+            self.head = self.nodes.len() - 1;
+        }
+    }
+
+    // The log entries up to a message, with the messages summaries stand in for written out
+    fn log_entries(&self, id: usize) -> Vec<String> {
+        let mut entries = vec![];
+        for id in self.path_to(id) {
+            let node = &self.nodes[id];
+            if let Some(compacted) = node.compacts {
+                entries.extend(self.log_entries(compacted).into_iter().skip(1));
+            }
+            entries.push(format_log_entry(&node.message));
+        }
+        entries
+    }
+
+    pub fn set_context(&mut self, context: Option<Message>) {
+        self.context = context;
+    }
+
+    // The messages sent to the API, with the context right before the last one
+    pub fn request_messages(&self) -> Vec<&Message> {
+        let mut messages = self.messages();
+        if let Some(context) = &self.context {
+            messages.insert(messages.len() - 1, context);
+        }
+        messages
+    }
+
+    pub fn push(&mut self, message: Message) {
+        // A message left unanswered by a cancelled request stays in its own branch
+        if message.role == "user" && self.head != 0 && self.nodes[self.head].message.role == "user"
+        {
+            self.head = self.nodes[self.head].parent.unwrap();
+        }
+
+        self.nodes.push(Node {
+            message,
+            parent: Some(self.head),
+            compacts: None,
+        });
+        self.head = self.nodes.len() - 1;
+    }
+
+    pub fn clear(&mut self, prompt: &str) {
+        self.nodes.truncate(1);
+        self.nodes[0].message = Message::user(prompt);
+        self.head = 0;
+        self.context = None;
+    }
+
+    pub fn set_prompt(&mut self, prompt: &str) {
+        self.nodes[0].message.content = Content::Text(prompt.to_string());
+    }
+
+    // Steps back from the last answer, so the next one becomes its sibling. Returns false if there is nothing to send
+    pub fn retry(&mut self) -> bool {
+        if self.head == 0 {
+            return false;
+        }
+
+        if self.nodes[self.head].message.role == "assistant" {
+            self.head = self.nodes[self.head].parent.unwrap();
+        }
+        self.head != 0
+    }
+
+    // Steps back to before the last user message, which stays in /branches
+    pub fn undo(&mut self) -> bool {
+        match self.user_message_index(-1) {
+            Some(index) => {
+                self.rewind(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Position in messages() of the nth user message (1-based, negative counts from the end), not counting the prompt
+    pub fn user_message_index(&self, n: isize) -> Option<usize> {
+        let indices = self
+            .messages()
+            .iter()
+            .enumerate()
+            .skip(1)
+            .filter(|(_, message)| message.role == "user")
+            .map(|(i, _)| i)
+            .collect::<Vec<usize>>();
+
+        let position = if n < 0 {
+            indices.len().checked_sub(n.unsigned_abs())?
+        } else {
+            (n as usize).checked_sub(1)?
+        };
+
+        indices.get(position).copied()
+    }
+
+    pub fn user_message_count(&self) -> usize {
+        self.messages()
+            .iter()
+            .skip(1)
+            .filter(|message| message.role == "user")
+            .count()
+    }
+
+    // Steps back to before messages()[index], so the next message starts a new branch there
+    pub fn rewind(&mut self, index: usize) {
+        if let Some(parent) = index
+            .checked_sub(1)
+            .and_then(|i| self.path().get(i).copied())
+        {
+            self.head = parent;
+        }
+    }
+
+    // The last message of every branch, in the order they are listed by /branches
+    fn leaves(&self) -> Vec<usize> {
+        let mut leaves = vec![];
+        let mut stack = vec![0];
+        while let Some(id) = stack.pop() {
+            let children = self.children(id);
+            if children.is_empty() {
+                leaves.push(id);
+            }
+            stack.extend(children.into_iter().rev());
+        }
+        leaves
+    }
+
+    pub fn branch_count(&self) -> usize {
+        self.leaves().len()
+    }
+
+    // Switches to the nth branch (1-based)
+    pub fn checkout(&mut self, n: usize) -> bool {
+        match self.leaves().get(n.wrapping_sub(1)) {
+            Some(&leaf) => {
+                self.head = leaf;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn describe(&self, id: usize, leaves: &[usize], path: &[usize]) -> String {
+        let message = &self.nodes[id].message;
+        let label = match (id, message.role.as_str()) {
+            (0, _) => "Prompt",
+            (_, "user") => "User",
+            (_, "assistant") => "GPT",
+            (_, role) => role,
+        };
+
+        let content = message.content.to_string();
+        let preview = content.lines().next().unwrap_or("");
+        let preview = match preview.char_indices().nth(60) {
+            Some((end, _)) => format!("{}...", &preview[..end]),
+            None => preview.to_string(),
+        };
+
+        let mut line = format!("{}: {}", label, preview);
+        if let Some(n) = leaves.iter().position(|&leaf| leaf == id) {
+            line = format!("[{}] {}", n + 1, line);
+        }
+        if id == self.head {
+            line.push_str(" (current)");
+        }
+
+        // The current branch stands out
+        if path.contains(&id) {
+            line.bold().to_string()
+        } else {
+            line
+        }
+    }
+
+    fn tree_lines(
+        &self,
+        id: usize,
+        (first, rest): (&str, &str),
+        (leaves, path): (&[usize], &[usize]),
+        lines: &mut Vec<String>,
+    ) {
+        lines.push(format!("{}{}", first, self.describe(id, leaves, path)));
+
+        let children = self.children(id);
+        // Linear stretches are not indented, so only the forks show up as steps
+        if children.len() == 1 {
+            self.tree_lines(children[0], (rest, rest), (leaves, path), lines);
+            return;
+        }
+
+        for (i, &child) in children.iter().enumerate() {
+            let (branch, continuation) = if i == children.len() - 1 {
+                ("└─ ", "   ")
+            } else {
+                ("├─ ", "│  ")
+            };
+            self.tree_lines(
+                child,
+                (
+                    &format!("{}{}", rest, branch),
+                    &format!("{}{}", rest, continuation),
+                ),
+                (leaves, path),
+                lines,
+            );
+        }
+    }
+
+    // The whole tree, with the branches numbered for /checkout and the current one marked
+    pub fn tree(&self) -> String {
+        let leaves = self.leaves();
+        let path = self.path();
+        let mut lines = vec![];
+        self.tree_lines(0, ("", ""), (&leaves, &path), &mut lines);
+        lines.join("\n")
+    }
+
+    // Writes <start time>.json with the whole tree, and <start time>.log with the current branch
+    pub async fn save_log(&self, logs_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        if !logs_dir.exists() {
+            tokio::fs::create_dir_all(logs_dir).await?;
+        }
+
+        let name = self.started.format("%Y-%m-%d_%H-%M-%S").to_string();
+
+        let log_file = tokio::fs::File::create(logs_dir.join(format!("{}.json", name))).await?;
+        let mut log_file = tokio::io::BufWriter::new(log_file);
+
+        log_file
+            .write_all(serde_json::to_string(self)?.as_bytes())
+            .await?;
+        // Ensure the data is written to disk
+        log_file.flush().await?;
+
+        let mut log_file_content = String::new();
+        for entry in self.log_entries(self.head) {
+            log_file_content.push_str(&entry);
+            log_file_content.push_str("\n\n");
+        }
+
+        let log_file = tokio::fs::File::create(logs_dir.join(format!("{}.log", name))).await?;
+        let mut log_file = tokio::io::BufWriter::new(log_file);
+
+        log_file.write_all(log_file_content.as_bytes()).await?;
+        log_file.flush().await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(conversation: &Conversation) -> Vec<String> {
+        conversation
+            .messages()
+            .iter()
+            .map(|message| message.content.text())
+            .collect()
+    }
+
+    // The prompt, then a question and an answer for each of the exchanges
+    fn conversation(exchanges: &[(&str, &str)]) -> Conversation {
+        let mut conversation = Conversation::new("prompt");
+        for (question, answer) in exchanges {
+            conversation.push(Message::user(question));
+            conversation.push(Message::assistant(answer));
+        }
+        conversation
+    }
+
+    #[test]
+    fn push_continues_the_current_branch() {
+        let conversation = conversation(&[("q1", "a1"), ("q2", "a2")]);
+        assert_eq!(texts(&conversation), ["prompt", "q1", "a1", "q2", "a2"]);
+        assert_eq!(conversation.branch_count(), 1);
+    }
+
+    #[test]
+    fn push_after_an_unanswered_message_starts_a_sibling() {
+        let mut conversation = conversation(&[("q1", "a1")]);
+        conversation.push(Message::user("cancelled"));
+        conversation.push(Message::user("q2"));
+
+        assert_eq!(texts(&conversation), ["prompt", "q1", "a1", "q2"]);
+        assert_eq!(conversation.branch_count(), 2);
+    }
+
+    #[test]
+    fn retry_adds_a_sibling_answer() {
+        let mut conversation = conversation(&[("q1", "a1")]);
+        assert!(conversation.retry());
+        assert_eq!(texts(&conversation), ["prompt", "q1"]);
+
+        conversation.push(Message::assistant("a1 again"));
+        assert_eq!(texts(&conversation), ["prompt", "q1", "a1 again"]);
+        assert_eq!(conversation.branch_count(), 2);
+
+        assert!(conversation.checkout(1));
+        assert_eq!(texts(&conversation), ["prompt", "q1", "a1"]);
+    }
+
+    #[test]
+    fn retry_needs_a_message() {
+        let mut conversation = Conversation::new("prompt");
+        assert!(!conversation.retry());
+    }
+
+    #[test]
+    fn rewind_branches_before_a_message() {
+        let mut conversation = conversation(&[("q1", "a1"), ("q2", "a2")]);
+        let index = conversation.user_message_index(2).unwrap();
+        assert_eq!(index, 3);
+
+        conversation.rewind(index);
+        assert_eq!(texts(&conversation), ["prompt", "q1", "a1"]);
+
+        conversation.push(Message::user("q2 edited"));
+        conversation.push(Message::assistant("a2 edited"));
+        assert_eq!(conversation.branch_count(), 2);
+        assert_eq!(conversation.user_message_count(), 2);
+    }
+
+    #[test]
+    fn undo_drops_the_last_exchange() {
+        let mut conversation = conversation(&[("q1", "a1"), ("q2", "a2")]);
+        assert!(conversation.undo());
+        assert_eq!(texts(&conversation), ["prompt", "q1", "a1"]);
+        assert!(conversation.undo());
+        assert_eq!(texts(&conversation), ["prompt"]);
+        assert!(!conversation.undo());
+    }
+
+    #[test]
+    fn leaves_are_listed_depth_first() {
+        let mut conversation = conversation(&[("q1", "a1"), ("q2", "a2")]);
+        conversation.rewind(1);
+        conversation.push(Message::user("other"));
+        conversation.checkout(1);
+        conversation.rewind(3);
+        conversation.push(Message::user("q2 edited"));
+
+        assert_eq!(conversation.branch_count(), 3);
+        let last_messages = (1..=3)
+            .map(|n| {
+                conversation.checkout(n);
+                texts(&conversation).pop().unwrap()
+            })
+            .collect::<Vec<String>>();
+        assert_eq!(last_messages, ["a2", "q2 edited", "other"]);
+        assert!(!conversation.checkout(0));
+        assert!(!conversation.checkout(4));
+    }
+
+    #[test]
+    fn fork_keeps_only_the_current_branch() {
+        let mut conversation = conversation(&[("q1", "a1")]);
+        conversation.retry();
+        conversation.push(Message::assistant("a1 again"));
+
+        let fork = conversation.fork();
+        assert_eq!(texts(&fork), ["prompt", "q1", "a1 again"]);
+        assert_eq!(fork.branch_count(), 1);
+    }
+
+    #[test]
+    fn saved_messages_reads_trees_and_lists() {
+        let conversation = conversation(&[("q1", "a1")]);
+        let tree = serde_json::to_string(&conversation).unwrap();
+        let messages = saved_messages(&tree).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].content.text(), "q1");
+
+        let list = r#"[{"role":"user","content":"prompt"},{"role":"user","content":"old"}]"#;
+        let messages = saved_messages(list).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content.text(), "old");
+    }
+
+    #[test]
+    fn compactable_leaves_the_last_messages_from_a_question() {
+        let conversation = conversation(&[("q1", "a1"), ("q2", "a2"), ("q3", "a3")]);
+        let compactable = |keep| {
+            conversation
+                .compactable(keep)
+                .iter()
+                .map(|message| message.content.text())
+                .collect::<Vec<String>>()
+        };
+
+        assert_eq!(compactable(2), ["q1", "a1", "q2", "a2"]);
+        assert_eq!(compactable(3), ["q1", "a1"]);
+        assert!(compactable(6).is_empty());
+    }
+
+    #[test]
+    fn compact_replaces_older_messages_with_a_summary() {
+        let mut conversation = conversation(&[("q1", "a1"), ("q2", "a2"), ("q3", "a3")]);
+        conversation.compact(2, "S");
+
+        assert_eq!(
+            texts(&conversation),
+            [
+                "prompt",
+                "Summary of the earlier conversation:\n\nS",
+                "q3",
+                "a3"
+            ]
+        );
+        assert_eq!(conversation.branch_count(), 2);
+        // The log still has the summarized messages
+        assert_eq!(
+            conversation.log_entries(conversation.head),
+            [
+                "[User]\nprompt",
+                "[User]\nq1",
+                "[GPT]\na1",
+                "[User]\nq2",
+                "[GPT]\na2",
+                "[system]\nSummary of the earlier conversation:\n\nS",
+                "[User]\nq3",
+                "[GPT]\na3",
+            ]
+        );
+    }
+
+    #[test]
+    fn compacting_keeps_the_last_question_even_with_keep_0() {
+        let mut conversation = conversation(&[("q1", "a1"), ("q2", "a2")]);
+        conversation.push(Message::user("q3"));
+        assert_eq!(conversation.compactable(0).len(), 4);
+
+        conversation.compact(0, "S");
+        assert_eq!(
+            texts(&conversation),
+            ["prompt", "Summary of the earlier conversation:\n\nS", "q3"]
+        );
+
+        let mut conversation = Conversation::new("prompt");
+        conversation.push(Message::user("q1"));
+        assert!(conversation.compactable(0).is_empty());
+    }
+}
@@ -0,0 +1,518 @@
+// Decodes the image formats attachments can be in, to draw them in the terminal
+
+use std::{io::Cursor, num::NonZeroU64};
+
+// Larger images are refused before anything is allocated for them, as a few bytes of header
+// can claim any size
+const MAX_PIXELS: usize = 8192 * 8192;
+// What the decoders may allocate besides the pixels, for chunks, tables and buffers
+const MAX_DECODER_BYTES: usize = 64 * 1024 * 1024;
+
+pub struct Pixels {
+    pub width: usize,
+    pub height: usize,
+    pub rgba: Vec<[u8; 4]>,
+}
+
+impl Pixels {
+    pub fn get(&self, x: usize, y: usize) -> [u8; 4] {
+        self.rgba[y * self.width + x]
+    }
+
+    // Nearest neighbour scaling, good enough for previews
+    pub fn resize(&self, width: usize, height: usize) -> Pixels {
+        let mut rgba = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                rgba.push(self.get(x * self.width / width, y * self.height / height));
+            }
+        }
+
+        Pixels {
+            width,
+            height,
+            rgba,
+        }
+    }
+}
+
+pub fn is_png(data: &[u8]) -> bool {
+    data.starts_with(b"\x89PNG\r\n\x1a\n")
+}
+
+fn is_jpeg(data: &[u8]) -> bool {
+    data.starts_with(b"\xff\xd8\xff")
+}
+
+fn is_gif(data: &[u8]) -> bool {
+    data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a")
+}
+
+fn is_webp(data: &[u8]) -> bool {
+    data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP"
+}
+
+fn check_size(width: usize, height: usize) -> Result<(), Box<dyn std::error::Error>> {
+    // Scaling divides by the size
+    if width == 0 || height == 0 {
+        return Err("the image has no pixels".into());
+    }
+    if width.saturating_mul(height) > MAX_PIXELS {
+        return Err(format!(
+            "the image is too large to show ({}x{} pixels)",
+            width, height
+        )
+        .into());
+    }
+    Ok(())
+}
+
+// 8 bit samples with 1 to 4 channels, gray, gray and alpha, RGB or RGBA
+fn to_rgba(samples: &[u8], channels: usize) -> Vec<[u8; 4]> {
+    samples
+        .chunks_exact(channels)
+        .map(|pixel| match pixel {
+            [l] => [*l, *l, *l, 255],
+            [l, a] => [*l, *l, *l, *a],
+            [r, g, b] => [*r, *g, *b, 255],
+            _ => [pixel[0], pixel[1], pixel[2], pixel[3]],
+        })
+        .collect()
+}
+
+// Palettes and low bit depths are expanded and 16 bit samples cut to 8 bits, so every PNG comes
+// out as one of the 8 bit color types. Interlaced ones are put back in order
+fn decode_png(data: &[u8]) -> Result<Pixels, Box<dyn std::error::Error>> {
+    let mut decoder = png::Decoder::new_with_limits(
+        data,
+        png::Limits {
+            bytes: MAX_DECODER_BYTES,
+        },
+    );
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let (width, height) = reader.info().size();
+    let (width, height) = (width as usize, height as usize);
+    check_size(width, height)?;
+
+    let mut samples = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut samples)?;
+    let channels = info.color_type.samples();
+
+    Ok(Pixels {
+        width,
+        height,
+        rgba: samples
+            .chunks_exact(info.line_size)
+            .take(height)
+            .flat_map(|row| to_rgba(&row[..width * channels], channels))
+            .collect(),
+    })
+}
+
+fn decode_jpeg(data: &[u8]) -> Result<Pixels, Box<dyn std::error::Error>> {
+    let mut decoder = jpeg_decoder::Decoder::new(data);
+    decoder.read_info()?;
+    let info = decoder.info().ok_or("no JPEG header")?;
+    check_size(info.width as usize, info.height as usize)?;
+    decoder.set_max_decoding_buffer_size(MAX_PIXELS * 4);
+    let samples = decoder.decode()?;
+
+    let rgba = match info.pixel_format {
+        jpeg_decoder::PixelFormat::L8 => to_rgba(&samples, 1),
+        // Big endian, so the first byte is the high one
+        jpeg_decoder::PixelFormat::L16 => samples
+            .chunks_exact(2)
+            .map(|l| [l[0], l[0], l[0], 255])
+            .collect(),
+        jpeg_decoder::PixelFormat::RGB24 => to_rgba(&samples, 3),
+        // Adobe JPEGs store CMYK inverted
+        jpeg_decoder::PixelFormat::CMYK32 => samples
+            .chunks_exact(4)
+            .map(|cmyk| {
+                let k = cmyk[3] as u16;
+                let channel = |c: u8| (c as u16 * k / 255) as u8;
+                [channel(cmyk[0]), channel(cmyk[1]), channel(cmyk[2]), 255]
+            })
+            .collect(),
+    };
+
+    Ok(Pixels {
+        width: info.width as usize,
+        height: info.height as usize,
+        rgba,
+    })
+}
+
+// The first frame, placed on the GIF's canvas
+fn decode_gif(data: &[u8]) -> Result<Pixels, Box<dyn std::error::Error>> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::RGBA);
+    options.set_memory_limit(gif::MemoryLimit::Bytes(
+        NonZeroU64::new(MAX_PIXELS as u64 * 4).unwrap(),
+    ));
+    let mut decoder = options.read_info(data)?;
+    let (width, height) = (decoder.width() as usize, decoder.height() as usize);
+    check_size(width, height)?;
+
+    let frame = decoder.read_next_frame()?.ok_or("the GIF has no frames")?;
+    let mut rgba = vec![[0, 0, 0, 0]; width * height];
+    for (i, pixel) in frame.buffer.chunks_exact(4).enumerate() {
+        let x = frame.left as usize + i % frame.width as usize;
+        let y = frame.top as usize + i / frame.width as usize;
+        if x < width && y < height {
+            rgba[y * width + x] = [pixel[0], pixel[1], pixel[2], pixel[3]];
+        }
+    }
+
+    Ok(Pixels {
+        width,
+        height,
+        rgba,
+    })
+}
+
+fn decode_webp(data: &[u8]) -> Result<Pixels, Box<dyn std::error::Error>> {
+    let mut decoder = image_webp::WebPDecoder::new(Cursor::new(data))?;
+    decoder.set_memory_limit(MAX_DECODER_BYTES);
+    let (width, height) = decoder.dimensions();
+    check_size(width as usize, height as usize)?;
+    let channels = if decoder.has_alpha() { 4 } else { 3 };
+
+    let size = decoder
+        .output_buffer_size()
+        .ok_or("the WebP is too large")?;
+    let mut samples = vec![0; size];
+    decoder.read_image(&mut samples)?;
+
+    Ok(Pixels {
+        width: width as usize,
+        height: height as usize,
+        rgba: to_rgba(&samples, channels),
+    })
+}
+
+pub fn decode(data: &[u8]) -> Result<Pixels, Box<dyn std::error::Error>> {
+    let pixels = if is_png(data) {
+        decode_png(data).map_err(|e| format!("invalid PNG: {}", e))?
+    } else if is_jpeg(data) {
+        decode_jpeg(data).map_err(|e| format!("invalid JPEG: {}", e))?
+    } else if is_gif(data) {
+        decode_gif(data).map_err(|e| format!("invalid GIF: {}", e))?
+    } else if is_webp(data) {
+        decode_webp(data).map_err(|e| format!("invalid WebP: {}", e))?
+    } else {
+        return Err("not a PNG, JPEG, GIF or WebP image".into());
+    };
+
+    if pixels.rgba.len() != pixels.width * pixels.height {
+        return Err("the image data doesn't match its size".into());
+    }
+    Ok(pixels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(kind: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(kind);
+        chunk.extend_from_slice(data);
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(kind);
+        crc.update(data);
+        chunk.extend_from_slice(&crc.finalize().to_be_bytes());
+        chunk
+    }
+
+    // A PNG of scanlines which already start with their filter byte, with extra chunks before IDAT
+    fn png(
+        (width, height): (u32, u32),
+        (depth, color_type, interlace): (u8, u8, u8),
+        scanlines: &[u8],
+        extra: &[(&[u8], &[u8])],
+    ) -> Vec<u8> {
+        let mut header = width.to_be_bytes().to_vec();
+        header.extend_from_slice(&height.to_be_bytes());
+        header.extend_from_slice(&[depth, color_type, 0, 0, interlace]);
+
+        let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+        data.extend(chunk(b"IHDR", &header));
+        for (kind, contents) in extra {
+            data.extend(chunk(kind, contents));
+        }
+        data.extend(chunk(
+            b"IDAT",
+            &miniz_oxide::deflate::compress_to_vec_zlib(scanlines, 6),
+        ));
+        data.extend(chunk(b"IEND", &[]));
+        data
+    }
+
+    #[test]
+    fn decodes_png_color_types() {
+        let rgba = decode(&png(
+            (2, 1),
+            (8, 6, 0),
+            &[0, 255, 0, 0, 255, 0, 0, 255, 128],
+            &[],
+        ));
+        assert_eq!(rgba.unwrap().rgba, [[255, 0, 0, 255], [0, 0, 255, 128]]);
+
+        let rgb = decode(&png((1, 1), (8, 2, 0), &[0, 1, 2, 3], &[]));
+        assert_eq!(rgb.unwrap().rgba, [[1, 2, 3, 255]]);
+
+        let gray_alpha = decode(&png((1, 1), (8, 4, 0), &[0, 9, 200], &[]));
+        assert_eq!(gray_alpha.unwrap().rgba, [[9, 9, 9, 200]]);
+    }
+
+    #[test]
+    fn scales_png_samples_to_8_bits() {
+        let gray = decode(&png((3, 1), (1, 0, 0), &[0, 0b1010_0000], &[]));
+        assert_eq!(
+            gray.unwrap().rgba,
+            [[255, 255, 255, 255], [0, 0, 0, 255], [255, 255, 255, 255]]
+        );
+
+        // 16 bit samples keep their high byte
+        let rgb = decode(&png((1, 1), (16, 2, 0), &[0, 1, 2, 3, 4, 5, 6], &[]));
+        assert_eq!(rgb.unwrap().rgba, [[1, 3, 5, 255]]);
+    }
+
+    #[test]
+    fn looks_up_png_palette_colors_and_transparency() {
+        let pixels = decode(&png(
+            (3, 1),
+            (2, 3, 0),
+            &[0, 0b0001_1000],
+            &[
+                (b"PLTE", &[10, 20, 30, 40, 50, 60, 70, 80, 90]),
+                (b"tRNS", &[0]),
+            ],
+        ));
+        assert_eq!(
+            pixels.unwrap().rgba,
+            [[10, 20, 30, 0], [40, 50, 60, 255], [70, 80, 90, 255]]
+        );
+    }
+
+    #[test]
+    fn reverses_png_filters() {
+        let pixels = decode(&png(
+            (2, 4),
+            (8, 0, 0),
+            &[1, 10, 5, 2, 1, 1, 3, 2, 2, 4, 1, 1],
+            &[],
+        ));
+        let gray = pixels
+            .unwrap()
+            .rgba
+            .iter()
+            .map(|rgba| rgba[0])
+            .collect::<Vec<u8>>();
+        assert_eq!(gray, [10, 15, 11, 16, 7, 13, 8, 14]);
+    }
+
+    #[test]
+    fn decodes_interlaced_pngs() {
+        // In a 2x2 image Adam7 pass 1 has the top left pixel, pass 6 the top right one and
+        // pass 7 the bottom row
+        let pixels = decode(&png((2, 2), (8, 0, 1), &[0, 10, 0, 20, 0, 30, 40], &[]));
+        let gray = pixels
+            .unwrap()
+            .rgba
+            .iter()
+            .map(|rgba| rgba[0])
+            .collect::<Vec<u8>>();
+        assert_eq!(gray, [10, 20, 30, 40]);
+    }
+
+    #[test]
+    fn refuses_huge_pngs_before_decoding_them() {
+        for size in [
+            (0xffff_ffff, 0xffff_ffff),
+            (0x7fff_ffff, 0x7fff_ffff),
+            (100_000, 100_000),
+        ] {
+            let data = png(size, (16, 6, 0), &[0; 9], &[]);
+            assert!(data.len() < 200);
+            assert!(decode(&data).is_err(), "{:?}", size);
+        }
+
+        let error = decode(&png((100_000, 100_000), (8, 6, 0), &[0; 9], &[]));
+        assert_eq!(
+            error.err().unwrap().to_string(),
+            "invalid PNG: the image is too large to show (100000x100000 pixels)"
+        );
+    }
+
+    #[test]
+    fn only_decodes_the_png_data_the_image_needs() {
+        // Megabytes of zeros, compressed to a few kilobytes, for a single pixel
+        let pixels = decode(&png((1, 1), (8, 0, 0), &vec![0; 4 * 1024 * 1024], &[])).unwrap();
+        assert_eq!(pixels.rgba, [[0, 0, 0, 255]]);
+    }
+
+    #[test]
+    fn rejects_broken_pngs() {
+        let truncated = png((4, 4), (8, 6, 0), &[0; 17], &[]);
+        assert!(decode(&truncated).is_err());
+        assert!(decode(&truncated[..20]).is_err());
+
+        for size in [(0, 0), (0, 1), (1, 0)] {
+            assert!(decode(&png(size, (8, 6, 0), &[0], &[])).is_err());
+        }
+        // Depths the color type doesn't allow, and an unknown filter
+        assert!(decode(&png((1, 1), (16, 3, 0), &[0; 9], &[])).is_err());
+        assert!(decode(&png((1, 1), (4, 2, 0), &[0; 9], &[])).is_err());
+        assert!(decode(&png((1, 1), (8, 0, 0), &[7, 0], &[])).is_err());
+    }
+
+    #[test]
+    fn decodes_jpeg() {
+        let mut data = vec![];
+        jpeg_encoder::Encoder::new(&mut data, 100)
+            .encode(
+                &[200, 30, 30].repeat(16 * 8),
+                16,
+                8,
+                jpeg_encoder::ColorType::Rgb,
+            )
+            .unwrap();
+
+        let pixels = decode(&data).unwrap();
+        assert_eq!((pixels.width, pixels.height), (16, 8));
+        // Lossy, so only close to the original
+        let [r, g, b, a] = pixels.get(5, 5);
+        assert!(
+            r > 180 && g < 60 && b < 60 && a == 255,
+            "{:?}",
+            [r, g, b, a]
+        );
+    }
+
+    #[test]
+    fn decodes_grayscale_jpeg() {
+        let mut data = vec![];
+        jpeg_encoder::Encoder::new(&mut data, 100)
+            .encode(&[128; 64], 8, 8, jpeg_encoder::ColorType::Luma)
+            .unwrap();
+
+        let [r, g, b, a] = decode(&data).unwrap().get(0, 0);
+        assert!(r.abs_diff(128) < 4 && r == g && g == b && a == 255);
+    }
+
+    #[test]
+    fn places_the_first_gif_frame_on_its_canvas() {
+        let mut data = vec![];
+        {
+            let mut encoder = gif::Encoder::new(&mut data, 3, 2, &[]).unwrap();
+            let mut frame =
+                gif::Frame::from_palette_pixels(2, 1, [0, 1], [255, 0, 0, 0, 0, 255], None);
+            frame.left = 1;
+            frame.top = 1;
+            encoder.write_frame(&frame).unwrap();
+            encoder
+                .write_frame(&gif::Frame::from_palette_pixels(
+                    3,
+                    2,
+                    [0; 6],
+                    [0, 255, 0],
+                    None,
+                ))
+                .unwrap();
+        }
+
+        let pixels = decode(&data).unwrap();
+        assert_eq!((pixels.width, pixels.height), (3, 2));
+        assert_eq!(
+            pixels.rgba,
+            [
+                [0, 0, 0, 0],
+                [0, 0, 0, 0],
+                [0, 0, 0, 0],
+                [0, 0, 0, 0],
+                [255, 0, 0, 255],
+                [0, 0, 255, 255]
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_webp() {
+        let rgba = [
+            [255, 0, 0, 255],
+            [0, 255, 0, 128],
+            [0, 0, 255, 0],
+            [9, 9, 9, 255],
+        ];
+        let mut data = vec![];
+        image_webp::WebPEncoder::new(&mut data)
+            .encode(&rgba.concat(), 2, 2, image_webp::ColorType::Rgba8)
+            .unwrap();
+
+        let pixels = decode(&data).unwrap();
+        assert_eq!((pixels.width, pixels.height), (2, 2));
+        assert_eq!(pixels.rgba[..2], rgba[..2]);
+        assert_eq!(pixels.rgba[3], rgba[3]);
+    }
+
+    #[test]
+    fn decodes_opaque_webp() {
+        let mut data = vec![];
+        image_webp::WebPEncoder::new(&mut data)
+            .encode(&[1, 2, 3], 1, 1, image_webp::ColorType::Rgb8)
+            .unwrap();
+
+        assert_eq!(decode(&data).unwrap().rgba, [[1, 2, 3, 255]]);
+    }
+
+    #[test]
+    fn rejects_other_and_broken_data() {
+        assert_eq!(
+            decode(b"BM not an image").err().unwrap().to_string(),
+            "not a PNG, JPEG, GIF or WebP image"
+        );
+        assert!(decode(b"\xff\xd8\xff\xe0 broken")
+            .err()
+            .unwrap()
+            .to_string()
+            .starts_with("invalid JPEG"));
+        assert!(decode(b"GIF89a")
+            .err()
+            .unwrap()
+            .to_string()
+            .starts_with("invalid GIF"));
+        assert!(decode(b"RIFF\0\0\0\0WEBPVP8L")
+            .err()
+            .unwrap()
+            .to_string()
+            .starts_with("invalid WebP"));
+    }
+
+    #[test]
+    fn refuses_huge_gifs() {
+        let mut data = vec![];
+        {
+            // A small frame on a huge canvas
+            let mut encoder = gif::Encoder::new(&mut data, 65535, 65535, &[0, 0, 0]).unwrap();
+            encoder
+                .write_frame(&gif::Frame::from_palette_pixels(1, 1, [0], [0, 0, 0], None))
+                .unwrap();
+        }
+
+        assert_eq!(
+            decode(&data).err().unwrap().to_string(),
+            "invalid GIF: the image is too large to show (65535x65535 pixels)"
+        );
+    }
+
+    #[test]
+    fn rejects_empty_gifs() {
+        let mut data = vec![];
+        gif::Encoder::new(&mut data, 0, 0, &[0, 0, 0]).unwrap();
+
+        assert!(decode(&data).is_err());
+    }
+}
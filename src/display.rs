@@ -0,0 +1,314 @@
+use std::{
+    collections::BTreeSet,
+    io::{stdout, IsTerminal, Write},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use crossterm::{
+    style::{Color, Stylize},
+    terminal,
+};
+
+use crate::{
+    config::AppConfig,
+    decode::{self, Pixels},
+};
+
+// Images never take more columns than this, however wide the terminal is
+const MAX_COLUMNS: usize = 64;
+// Terminals don't report their cell size, this is a common one
+const CELL_WIDTH: usize = 10;
+const CELL_HEIGHT: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Kitty,
+    Iterm2,
+    Sixel,
+    // Unicode half blocks, which work in any terminal with true color
+    Blocks,
+}
+
+pub struct InlineImages {
+    protocol: Option<Protocol>,
+}
+
+fn env_is(name: &str, value: &str) -> bool {
+    std::env::var(name).is_ok_and(|v| v == value)
+}
+
+// Guesses from the environment, since asking the terminal would mean reading its reply from stdin
+fn detect_protocol() -> Option<Protocol> {
+    if !stdout().is_terminal()
+        || std::env::var("NO_COLOR").is_ok_and(|v| !v.is_empty())
+        || env_is("TERM", "dumb")
+    {
+        return None;
+    }
+
+    // tmux and screen swallow the graphics escape codes
+    if std::env::var("TMUX").is_ok() || std::env::var("TERM").is_ok_and(|t| t.starts_with("screen"))
+    {
+        return Some(Protocol::Blocks);
+    }
+
+    let term = std::env::var("TERM").unwrap_or_default();
+    let term_program = std::env::var("TERM_PROGRAM").unwrap_or_default();
+
+    if std::env::var("KITTY_WINDOW_ID").is_ok()
+        || term == "xterm-kitty"
+        || term_program == "ghostty"
+    {
+        Some(Protocol::Kitty)
+    } else if term_program == "iTerm.app"
+        || term_program == "WezTerm"
+        || env_is("LC_TERMINAL", "iTerm2")
+    {
+        Some(Protocol::Iterm2)
+    } else if term.contains("sixel")
+        || ["foot", "mlterm", "contour"]
+            .iter()
+            .any(|name| term.starts_with(name))
+    {
+        Some(Protocol::Sixel)
+    } else {
+        Some(Protocol::Blocks)
+    }
+}
+
+fn columns() -> usize {
+    terminal::size()
+        .map_or(80, |(columns, _)| columns as usize)
+        .min(MAX_COLUMNS)
+}
+
+// Fits the image in `columns` terminal cells, scaling down only
+fn fit(pixels: &Pixels, columns: usize) -> Pixels {
+    let max_width = columns * CELL_WIDTH;
+    if pixels.width <= max_width {
+        return pixels.resize(pixels.width, pixels.height);
+    }
+    let height = (pixels.height * max_width / pixels.width).max(1);
+    pixels.resize(max_width, height)
+}
+
+fn kitty(data: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+    // PNGs are sent as they are, other formats as RGBA pixels
+    let (format, payload) = if decode::is_png(data) {
+        ("f=100".to_string(), data.to_vec())
+    } else {
+        let pixels = fit(&decode::decode(data)?, columns());
+        (
+            format!("f=32,s={},v={}", pixels.width, pixels.height),
+            pixels.rgba.concat(),
+        )
+    };
+
+    // The payload is sent in chunks of at most 4096 bytes
+    let encoded = STANDARD.encode(payload);
+    let chunks = encoded.as_bytes().chunks(4096).collect::<Vec<&[u8]>>();
+    let mut out = String::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = if i + 1 < chunks.len() { 1 } else { 0 };
+        let keys = if i == 0 {
+            format!("a=T,{},c={},m={}", format, columns(), more)
+        } else {
+            format!("m={}", more)
+        };
+        out.push_str(&format!(
+            "\x1b_G{};{}\x1b\\",
+            keys,
+            std::str::from_utf8(chunk)?
+        ));
+    }
+    Ok(out)
+}
+
+fn iterm2(data: &[u8]) -> String {
+    format!(
+        "\x1b]1337;File=inline=1;size={};width={}:{}\x07",
+        data.len(),
+        columns(),
+        STANDARD.encode(data)
+    )
+}
+
+// Quantizes to the 6x6x6 color cube, pixels which are mostly transparent are left out
+fn cube_index(rgba: [u8; 4]) -> Option<usize> {
+    if rgba[3] < 128 {
+        return None;
+    }
+    let level = |channel: u8| (channel as usize * 5 + 127) / 255;
+    Some(level(rgba[0]) * 36 + level(rgba[1]) * 6 + level(rgba[2]))
+}
+
+fn sixel(data: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+    let pixels = fit(&decode::decode(data)?, columns());
+
+    // Transparent background, so unset pixels keep the terminal's
+    let mut out = format!("\x1bP0;1;0q\"1;1;{};{}", pixels.width, pixels.height);
+    for index in 0..216 {
+        let percent = |level: usize| level * 100 / 5;
+        out.push_str(&format!(
+            "#{};2;{};{};{}",
+            index,
+            percent(index / 36),
+            percent(index / 6 % 6),
+            percent(index % 6)
+        ));
+    }
+
+    // Each band is six pixel rows, drawn once per color it uses
+    for band in (0..pixels.height).step_by(6) {
+        let rows = band..(band + 6).min(pixels.height);
+        let colors = rows
+            .clone()
+            .flat_map(|y| (0..pixels.width).map(move |x| (x, y)))
+            .filter_map(|(x, y)| cube_index(pixels.get(x, y)))
+            .collect::<BTreeSet<usize>>();
+
+        for color in colors {
+            out.push_str(&format!("#{}", color));
+
+            let mut run: Option<(char, usize)> = None;
+            for x in 0..pixels.width {
+                let bits = rows
+                    .clone()
+                    .filter(|&y| cube_index(pixels.get(x, y)) == Some(color))
+                    .fold(0u8, |bits, y| bits | 1 << (y - band));
+                let sixel = (63 + bits) as char;
+
+                run = match run {
+                    Some((c, n)) if c == sixel => Some((c, n + 1)),
+                    Some((c, n)) => {
+                        push_run(&mut out, c, n);
+                        Some((sixel, 1))
+                    }
+                    None => Some((sixel, 1)),
+                };
+            }
+            if let Some((c, n)) = run {
+                push_run(&mut out, c, n);
+            }
+
+            // Back to the start of the band for the next color
+            out.push('$');
+        }
+        out.push('-');
+    }
+
+    out.push_str("\x1b\\");
+    Ok(out)
+}
+
+fn push_run(out: &mut String, c: char, n: usize) {
+    if n > 3 {
+        out.push_str(&format!("!{}{}", n, c));
+    } else {
+        out.extend(std::iter::repeat_n(c, n));
+    }
+}
+
+// Mostly transparent pixels are left to the terminal background
+fn cell_color(rgba: [u8; 4]) -> Option<Color> {
+    (rgba[3] >= 128).then_some(Color::Rgb {
+        r: rgba[0],
+        g: rgba[1],
+        b: rgba[2],
+    })
+}
+
+fn blocks(data: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+    let pixels = decode::decode(data)?;
+
+    // Each cell shows two pixels, the upper one in the foreground color of ▀
+    let columns = columns().min(pixels.width);
+    let rows = (pixels.height * columns * CELL_WIDTH / (pixels.width * CELL_HEIGHT)).max(1);
+    let pixels = pixels.resize(columns, rows * 2);
+
+    let mut out = String::new();
+    for row in 0..rows {
+        for x in 0..columns {
+            let top = cell_color(pixels.get(x, row * 2));
+            let bottom = cell_color(pixels.get(x, row * 2 + 1));
+            let cell = match (top, bottom) {
+                (Some(top), Some(bottom)) => "▀".with(top).on(bottom).to_string(),
+                (Some(top), None) => "▀".with(top).to_string(),
+                (None, Some(bottom)) => "▄".with(bottom).to_string(),
+                (None, None) => " ".to_string(),
+            };
+            out.push_str(&cell);
+        }
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+impl InlineImages {
+    pub fn new(config: &AppConfig) -> InlineImages {
+        let protocol = match config.inline_images.as_str() {
+            "auto" => detect_protocol(),
+            "kitty" => Some(Protocol::Kitty),
+            "iterm2" => Some(Protocol::Iterm2),
+            "sixel" => Some(Protocol::Sixel),
+            "blocks" => Some(Protocol::Blocks),
+            "off" => None,
+            setting => {
+                println!(
+                    "Unknown inline_images setting {}, using auto instead",
+                    setting.bold().red()
+                );
+                detect_protocol()
+            }
+        };
+
+        InlineImages { protocol }
+    }
+
+    // Draws the image below the cursor. Images the protocol can't show are skipped
+    pub fn show(&self, data: &[u8]) {
+        let Some(protocol) = self.protocol else {
+            return;
+        };
+
+        let image = match protocol {
+            Protocol::Kitty => kitty(data),
+            Protocol::Iterm2 => Ok(iterm2(data)),
+            Protocol::Sixel => sixel(data),
+            Protocol::Blocks => blocks(data),
+        };
+
+        match image {
+            Ok(image) => {
+                print!("{}", image);
+                if protocol != Protocol::Blocks {
+                    println!();
+                }
+                stdout().flush().unwrap();
+            }
+            Err(e) => println!("(Can't show the image inline: {})", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_draw_any_supported_format() {
+        let mut data = vec![];
+        image_webp::WebPEncoder::new(&mut data)
+            .encode(&[255, 0, 0, 0, 0, 255], 2, 1, image_webp::ColorType::Rgb8)
+            .unwrap();
+
+        let out = blocks(&data).unwrap();
+        assert_eq!(out.lines().count(), 1);
+        assert_eq!(out.matches('▀').count(), 2);
+    }
+
+    #[test]
+    fn blocks_report_images_they_cannot_draw() {
+        assert!(blocks(b"not an image").is_err());
+    }
+}
@@ -0,0 +1,50 @@
+use std::path::PathBuf;
+
+fn editor_command() -> String {
+    std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| {
+            if cfg!(windows) {
+                "notepad".to_string()
+            } else {
+                "vi".to_string()
+            }
+        })
+}
+
+fn temp_path() -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "gptcli-{}-{}.md",
+        std::process::id(),
+        chrono::Local::now().format("%Y%m%d%H%M%S%f")
+    ))
+}
+
+// Opens $VISUAL or $EDITOR on a temp file holding `initial`, and returns what was saved
+pub async fn edit(initial: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let path = temp_path();
+    tokio::fs::write(&path, initial).await?;
+
+    // Run through the shell so editors with arguments like "code --wait" work
+    let command = format!("{} \"{}\"", editor_command(), path.display());
+    #[cfg(windows)]
+    let status = tokio::process::Command::new("cmd")
+        .args(["/C", &command])
+        .status()
+        .await;
+    #[cfg(not(windows))]
+    let status = tokio::process::Command::new("sh")
+        .args(["-c", &command])
+        .status()
+        .await;
+
+    let contents = tokio::fs::read_to_string(&path).await;
+    tokio::fs::remove_file(&path).await?;
+
+    let status = status?;
+    if !status.success() {
+        return Err(format!("{} exited with {}", editor_command(), status).into());
+    }
+
+    Ok(contents?.trim().to_string())
+}
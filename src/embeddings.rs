@@ -0,0 +1,292 @@
+use std::{path::Path, time::UNIX_EPOCH};
+
+use crate::{
+    api,
+    config::{EmbeddingsConfig, OpenAIConfig},
+};
+
+// Inputs sent per embeddings request
+const BATCH_SIZE: usize = 100;
+// Size of the local embedder's vectors
+const LOCAL_DIMENSIONS: usize = 1024;
+
+pub enum Embedder<'a> {
+    // The embeddings endpoint, with the configured model
+    Api {
+        client: &'a reqwest::Client,
+        headers: &'a reqwest::header::HeaderMap,
+        openai: &'a OpenAIConfig,
+        model: &'a str,
+    },
+    // Hashed words and word pairs, which works offline but only matches shared words
+    Local,
+}
+
+impl<'a> Embedder<'a> {
+    pub fn new(
+        client: &'a reqwest::Client,
+        headers: &'a reqwest::header::HeaderMap,
+        openai: &'a OpenAIConfig,
+        config: &'a EmbeddingsConfig,
+    ) -> Result<Embedder<'a>, Box<dyn std::error::Error>> {
+        match config.embedder.as_str() {
+            "api" => Ok(Embedder::Api {
+                client,
+                headers,
+                openai,
+                model: &config.model,
+            }),
+            "local" => Ok(Embedder::Local),
+            embedder => Err(format!(
+                "Unknown embedder {}, expected \"api\" or \"local\"",
+                embedder
+            )
+            .into()),
+        }
+    }
+
+    // Stored with the vectors, as those of different embedders can't be compared
+    pub fn id(&self) -> String {
+        match self {
+            Embedder::Api { model, .. } => format!("api:{}", model),
+            Embedder::Local => format!("local:{}", LOCAL_DIMENSIONS),
+        }
+    }
+
+    // Normalized vectors, so their dot product is the cosine similarity
+    pub async fn embed(
+        &self,
+        texts: &[String],
+    ) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error>> {
+        let mut vectors = vec![];
+        match self {
+            Embedder::Api {
+                client,
+                headers,
+                openai,
+                model,
+            } => {
+                for batch in texts.chunks(BATCH_SIZE) {
+                    vectors
+                        .extend(request_embeddings(client, headers, openai, model, batch).await?);
+                }
+            }
+            Embedder::Local => vectors.extend(texts.iter().map(|text| local_embedding(text))),
+        }
+
+        for vector in &mut vectors {
+            normalize(vector);
+        }
+        Ok(vectors)
+    }
+}
+
+async fn request_embeddings(
+    client: &reqwest::Client,
+    headers: &reqwest::header::HeaderMap,
+    openai: &OpenAIConfig,
+    model: &str,
+    texts: &[String],
+) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error>> {
+    let body = serde_json::json!({
+        "model": model,
+        "input": texts,
+    });
+
+    let res = api::post_json(client, headers, openai, "embeddings", &body).await?;
+
+    let mut data = res["data"]
+        .as_array()
+        .ok_or("The response holds no embeddings")?
+        .clone();
+    // The order of the inputs, in case the response is not
+    data.sort_by_key(|embedding| embedding["index"].as_u64());
+    if data.len() != texts.len() {
+        return Err(format!("Expected {} embeddings, got {}", texts.len(), data.len()).into());
+    }
+
+    data.iter()
+        .map(|embedding| {
+            Ok(serde_json::from_value::<Vec<f32>>(
+                embedding["embedding"].clone(),
+            )?)
+        })
+        .collect()
+}
+
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+fn local_embedding(text: &str) -> Vec<f32> {
+    let mut vector = vec![0.0; LOCAL_DIMENSIONS];
+    let words = words(text);
+
+    let mut add = |feature: &str, weight: f32| {
+        let hash = crc32fast::hash(feature.as_bytes());
+        // The sign spreads collisions out instead of piling them up
+        let sign = if hash & 1 == 0 { 1.0 } else { -1.0 };
+        vector[(hash >> 1) as usize % LOCAL_DIMENSIONS] += sign * weight;
+    };
+    for word in &words {
+        add(word, 1.0);
+    }
+    for pair in words.windows(2) {
+        add(&format!("{} {}", pair[0], pair[1]), 0.5);
+    }
+
+    vector
+}
+
+fn normalize(vector: &mut [f32]) {
+    let length = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if length > 0.0 {
+        for x in vector {
+            *x /= length;
+        }
+    }
+}
+
+// Milliseconds since the epoch, files which changed since they were indexed are embedded again
+pub fn modified(path: &Path) -> Result<u64, Box<dyn std::error::Error>> {
+    let modified = std::fs::metadata(path)?.modified()?;
+    Ok(modified.duration_since(UNIX_EPOCH)?.as_millis() as u64)
+}
+
+pub fn similarity(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+// Splits text into chunks of at most max_chars, at paragraph and then line boundaries where possible
+pub fn chunk_text(text: &str, max_chars: usize) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut chunk = String::new();
+
+    let mut add = |piece: &str, separator: &str| {
+        if !chunk.is_empty()
+            && chunk.chars().count() + separator.len() + piece.chars().count() > max_chars
+        {
+            chunks.push(std::mem::take(&mut chunk));
+        }
+
+        // A single line longer than a chunk is cut up anywhere
+        let mut piece = piece;
+        while let Some((end, _)) = piece.char_indices().nth(max_chars) {
+            if !chunk.is_empty() {
+                chunks.push(std::mem::take(&mut chunk));
+            }
+            chunks.push(piece[..end].to_string());
+            piece = &piece[end..];
+        }
+
+        if !chunk.is_empty() {
+            chunk.push_str(separator);
+        }
+        chunk.push_str(piece);
+    };
+
+    for paragraph in text.split("\n\n").filter(|p| !p.trim().is_empty()) {
+        if paragraph.chars().count() > max_chars {
+            for line in paragraph.lines() {
+                add(line, "\n");
+            }
+        } else {
+            add(paragraph, "\n\n");
+        }
+    }
+
+    chunks.push(chunk);
+    chunks.retain(|chunk| !chunk.trim().is_empty());
+    chunks
+}
+
+// A chunk on a single line, cut to about max_chars
+pub fn snippet(text: &str, max_chars: usize) -> String {
+    let text = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_embeddings_stay_the_same() {
+        // Stored in index files, so a change here makes old indexes useless
+        let vector = local_embedding("Hello, world!");
+
+        assert_eq!(vector.len(), LOCAL_DIMENSIONS);
+        assert_eq!(vector[835], 1.0);
+        assert_eq!(vector[161], -1.0);
+        // The word pair "hello world"
+        assert_eq!(vector[194], -0.5);
+        assert_eq!(vector.iter().filter(|x| **x != 0.0).count(), 3);
+    }
+
+    #[test]
+    fn local_embeddings_ignore_case_and_punctuation() {
+        assert_eq!(
+            local_embedding("Hello, world!"),
+            local_embedding("hello world")
+        );
+        assert!(local_embedding("").iter().all(|x| *x == 0.0));
+    }
+
+    #[test]
+    fn normalized_vectors_have_unit_length() {
+        let mut vector = local_embedding("one two two three");
+        normalize(&mut vector);
+
+        assert!((similarity(&vector, &vector) - 1.0).abs() < 1e-6);
+
+        let mut empty = vec![0.0; 3];
+        normalize(&mut empty);
+        assert_eq!(empty, [0.0; 3]);
+    }
+
+    #[test]
+    fn chunk_text_keeps_paragraphs_together() {
+        assert_eq!(chunk_text("one\n\ntwo", 100), ["one\n\ntwo"]);
+        assert_eq!(chunk_text("aaaa\n\nbbbb", 6), ["aaaa", "bbbb"]);
+        assert_eq!(chunk_text("\n\n \n\none\n\n", 100), ["one"]);
+    }
+
+    #[test]
+    fn chunk_text_splits_long_paragraphs_at_lines() {
+        assert_eq!(chunk_text("aa\nbb\ncc", 5), ["aa\nbb", "cc"]);
+    }
+
+    #[test]
+    fn chunk_text_cuts_long_lines_anywhere() {
+        assert_eq!(chunk_text("abcdefghij", 4), ["abcd", "efgh", "ij"]);
+        assert_eq!(
+            chunk_text("ab\nabcdefghij", 4),
+            ["ab", "abcd", "efgh", "ij"]
+        );
+    }
+
+    #[test]
+    fn chunk_text_cuts_between_characters() {
+        assert_eq!(chunk_text("ééééé", 2), ["éé", "éé", "é"]);
+        assert_eq!(chunk_text("a€😀b", 3), ["a€😀", "b"]);
+
+        let text = "ünïcödé wörds\n".repeat(20);
+        for chunk in chunk_text(&text, 7) {
+            assert!(chunk.chars().count() <= 7, "{:?}", chunk);
+        }
+    }
+
+    #[test]
+    fn snippet_fits_on_a_line() {
+        assert_eq!(snippet("  one\n two\tthree ", 100), "one two three");
+        assert_eq!(snippet("one two three", 5), "one t...");
+        assert_eq!(snippet("ééé", 3), "ééé");
+        assert_eq!(snippet("éé😀é", 3), "éé😀...");
+    }
+}
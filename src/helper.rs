@@ -0,0 +1,248 @@
+use std::{
+    borrow::Cow,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crossterm::style::Stylize;
+use rustyline::{
+    completion::{Completer, FilenameCompleter, Pair},
+    highlight::Highlighter,
+    hint::Hinter,
+    validate::{ValidationContext, ValidationResult, Validator},
+    Cmd, ConditionalEventHandler, Context, Event, EventContext, Helper, RepeatCount,
+};
+
+pub const COMMANDS: &[&str] = &[
+    "/branches",
+    "/checkout",
+    "/clear",
+    "/compact",
+    "/config",
+    "/edit",
+    "/exit",
+    "/fork",
+    "/get",
+    "/image",
+    "/imagine",
+    "/model",
+    "/models",
+    "/prompt",
+    "/rag",
+    "/retry",
+    "/save",
+    "/set",
+    "/speak",
+    "/t",
+    "/theme",
+    "/transcribe",
+    "/undo",
+];
+
+const TRIPLE_QUOTE: &str = "\"\"\"";
+
+pub struct ReplHelper {
+    pub themes: Vec<String>,
+    pub config_keys: Vec<String>,
+    // From the cached model list, updated by /models
+    pub models: Vec<String>,
+    pub templates: Vec<String>,
+    // Set by the editor key binding, so the accepted line is opened in $EDITOR instead of sent
+    pub open_editor: Arc<AtomicBool>,
+    filename_completer: FilenameCompleter,
+}
+
+pub struct EditorKeyHandler(pub Arc<AtomicBool>);
+
+impl ConditionalEventHandler for EditorKeyHandler {
+    fn handle(
+        &self,
+        _evt: &Event,
+        _n: RepeatCount,
+        _positive: bool,
+        _ctx: &EventContext,
+    ) -> Option<Cmd> {
+        self.0.store(true, Ordering::SeqCst);
+        Some(Cmd::AcceptLine)
+    }
+}
+
+// Strips the triple quotes and line continuations of multi-line input
+pub fn normalize_input(input: &str) -> String {
+    let trimmed = input.trim();
+    if let Some(inner) = trimmed
+        .strip_prefix(TRIPLE_QUOTE)
+        .and_then(|rest| rest.strip_suffix(TRIPLE_QUOTE))
+    {
+        return inner.trim_matches(|c| c == '\r' || c == '\n').to_string();
+    }
+
+    input.replace("\\\r\n", "\n").replace("\\\n", "\n")
+}
+
+impl ReplHelper {
+    pub fn new(
+        themes: Vec<String>,
+        config_keys: Vec<String>,
+        models: Vec<String>,
+        templates: Vec<String>,
+    ) -> Self {
+        ReplHelper {
+            themes,
+            config_keys,
+            models,
+            templates,
+            open_editor: Arc::new(AtomicBool::new(false)),
+            filename_completer: FilenameCompleter::new(),
+        }
+    }
+
+    // Candidates for the first argument of a command
+    fn arguments(&self, command: &str) -> &[String] {
+        match command {
+            "/theme" => &self.themes,
+            "/set" | "/get" => &self.config_keys,
+            "/model" => &self.models,
+            "/t" => &self.templates,
+            _ => &[],
+        }
+    }
+
+    fn candidates(&self, line: &str) -> (usize, Vec<String>) {
+        if !line.starts_with('/') {
+            return (0, vec![]);
+        }
+
+        match line.split_once(' ') {
+            None => (
+                0,
+                COMMANDS
+                    .iter()
+                    .filter(|command| command.starts_with(line))
+                    .map(|command| command.to_string())
+                    .collect(),
+            ),
+            Some((command, arg)) => {
+                let arg = arg.trim_start();
+                if arg.contains(' ') {
+                    return (0, vec![]);
+                }
+
+                (
+                    line.len() - arg.len(),
+                    self.arguments(command)
+                        .iter()
+                        .filter(|candidate| candidate.starts_with(arg))
+                        .cloned()
+                        .collect(),
+                )
+            }
+        }
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let line = &line[..pos];
+
+        // @path mentions complete file names anywhere in the line
+        let word_start = line.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+        if let Some(path) = line[word_start..].strip_prefix('@') {
+            let (start, pairs) = self.filename_completer.complete_path(path, path.len())?;
+            return Ok((word_start + 1 + start, pairs));
+        }
+
+        if let Some(path) = line.strip_prefix("/image ") {
+            let (start, pairs) = self.filename_completer.complete_path(path, path.len())?;
+            return Ok(("/image ".len() + start, pairs));
+        }
+
+        let (start, candidates) = self.candidates(line);
+        Ok((
+            start,
+            candidates
+                .into_iter()
+                .map(|candidate| Pair {
+                    display: candidate.clone(),
+                    replacement: candidate,
+                })
+                .collect(),
+        ))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+
+    fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<String> {
+        if pos < line.len() {
+            return None;
+        }
+
+        let (start, candidates) = self.candidates(line);
+        match candidates.as_slice() {
+            [candidate] if candidate.len() > line.len() - start => {
+                Some(candidate[line.len() - start..].to_string())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Highlighter for ReplHelper {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        if !line.starts_with('/') {
+            return Cow::Borrowed(line);
+        }
+
+        let (command, rest) = line.split_at(line.find(' ').unwrap_or(line.len()));
+        if COMMANDS.contains(&command) {
+            Cow::Owned(format!("{}{}", command.green().bold(), rest))
+        } else {
+            Cow::Owned(format!("{}{}", command.red(), rest))
+        }
+    }
+
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        Cow::Owned(hint.dark_grey().to_string())
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
+        // The command color depends on the whole word, so redraw on every change
+        true
+    }
+}
+
+impl Validator for ReplHelper {
+    // Keeps reading lines until a """ block is closed, or while lines end with a backslash
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        let input = ctx.input();
+
+        if self.open_editor.load(Ordering::SeqCst) {
+            return Ok(ValidationResult::Valid(None));
+        }
+
+        if input.trim_start().starts_with(TRIPLE_QUOTE)
+            && input.matches(TRIPLE_QUOTE).count() % 2 == 1
+        {
+            return Ok(ValidationResult::Incomplete);
+        }
+
+        if input.ends_with('\\') {
+            return Ok(ValidationResult::Incomplete);
+        }
+
+        Ok(ValidationResult::Valid(None))
+    }
+}
+
+impl Helper for ReplHelper {}
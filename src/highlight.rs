@@ -0,0 +1,306 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+};
+
+use crossterm::style::Stylize;
+use regex::Regex;
+use syntect::dumps::{
+    dump_to_uncompressed_file, from_uncompressed_data, from_uncompressed_dump_file,
+};
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Style as HStyle, ThemeSet};
+use syntect::parsing::SyntaxSet;
+use syntect::util::{as_24_bit_terminal_escaped, LinesWithEndings};
+
+pub const DEFAULT_THEME: &str = "base16-ocean.dark";
+
+const THEME_SAMPLE: &str =
+    r#"fn main() { let answer: u32 = 42; println!("{}", answer); } // sample"#;
+
+pub struct Highlighter {
+    pub ps: SyntaxSet,
+    pub ts: ThemeSet,
+    pub theme: String,
+    code_re: Regex,
+}
+
+impl Highlighter {
+    pub fn new(
+        ps: SyntaxSet,
+        ts: ThemeSet,
+        theme: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let code_re = Regex::new(r#"```(?P<language>\w+)(?:\r?\n|\r)(?P<code>[\s\S]*?)\r?\n```"#)?;
+
+        let theme = if ts.themes.contains_key(theme) {
+            theme.to_string()
+        } else {
+            println!(
+                "Unknown syntax theme {}, falling back to {}. Available themes: {}",
+                theme.bold().red(),
+                DEFAULT_THEME.bold(),
+                ts.themes
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            );
+            DEFAULT_THEME.to_string()
+        };
+
+        Ok(Highlighter {
+            ps,
+            ts,
+            theme,
+            code_re,
+        })
+    }
+
+    pub fn highlight_message(&self, message: &str) -> String {
+        let mut message_mut = message.to_string();
+
+        for cap in self.code_re.captures_iter(message) {
+            message_mut =
+                message_mut.replace(&cap["language"], &cap["language"].grey().to_string());
+
+            let mut language = &cap["language"];
+
+            let syntax = self.ps.find_syntax_by_extension(language);
+            if syntax.is_none() {
+                match &cap["language"] {
+                    "rust" => language = "rs",
+                    "javascript" => language = "jsx",
+                    "python" => language = "py",
+                    "typescript" => language = "tsx",
+                    "c++" => language = "cpp",
+                    "c#" => language = "cs",
+                    "kotlin" => language = "kt",
+                    "ruby" => language = "erb",
+                    "bash" => language = "sh",
+                    "shell" => language = "sh",
+                    "sh" => language = "sh",
+                    "powershell" => language = "ps1",
+                    "elixir" => language = "ex",
+                    "erlang" => language = "erl",
+                    "haskell" => language = "hs",
+                    "webassembly" => language = "wasm",
+                    "assembly" => language = "asm",
+                    "markdown" => language = "md",
+                    _ => {}
+                }
+            }
+
+            // User syntaxes may only be registered under their name, not an extension
+            let syntax = self
+                .ps
+                .find_syntax_by_extension(language)
+                .or_else(|| self.ps.find_syntax_by_token(language));
+
+            let Some(syntax) = syntax else {
+                continue;
+            };
+
+            let mut h = HighlightLines::new(syntax, &self.ts.themes[self.theme.as_str()]);
+
+            let mut code: Vec<String> = vec![];
+            for line in LinesWithEndings::from(&cap["code"]) {
+                let ranges: Vec<(HStyle, &str)> = h.highlight_line(line, &self.ps).unwrap();
+                let mut escaped = as_24_bit_terminal_escaped(&ranges[..], false);
+                escaped.push_str("\x1b[0m");
+                code.push(escaped);
+            }
+
+            let stylized_code = code.join("\x1b[0m");
+
+            message_mut = message_mut.replace(&cap["code"], &stylized_code);
+        }
+
+        message_mut = message_mut.replace("```", "");
+
+        // for cap in tiny_code_re.captures_iter(message_mut.clone().as_str()) {
+        //     let bold = &cap["tinycode"].stylize().grey().italic().bold().to_string();
+        //     message_mut = message_mut.replace(format!("`{}`", &cap["tinycode"]).as_str(), bold);
+        // }
+
+        message_mut
+    }
+
+    pub fn theme_sample(&self, theme: &str) -> String {
+        let syntax = self
+            .ps
+            .find_syntax_by_extension("rs")
+            .unwrap_or_else(|| self.ps.find_syntax_plain_text());
+        let mut h = HighlightLines::new(syntax, &self.ts.themes[theme]);
+
+        match h.highlight_line(THEME_SAMPLE, &self.ps) {
+            Ok(ranges) => format!("{}\x1b[0m", as_24_bit_terminal_escaped(&ranges[..], true)),
+            Err(_) => THEME_SAMPLE.to_string(),
+        }
+    }
+
+    pub fn pick_theme(&self) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let names = self.ts.themes.keys().cloned().collect::<Vec<String>>();
+        let width = names.iter().map(|name| name.len()).max().unwrap_or(0);
+
+        let items = names
+            .iter()
+            .map(|name| format!("{:<width$}  {}", name, self.theme_sample(name)))
+            .collect::<Vec<String>>();
+
+        let selection = dialoguer::Select::new()
+            .with_prompt("Select a syntax theme")
+            .items(&items)
+            .default(
+                names
+                    .iter()
+                    .position(|name| *name == self.theme)
+                    .unwrap_or(0),
+            )
+            .interact_opt()?;
+
+        Ok(selection.map(|i| names[i].clone()))
+    }
+}
+
+fn find_syntax_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_syntax_files(&path, files)?;
+        } else if path.extension().is_some_and(|ext| ext == "sublime-syntax") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+// Fingerprint of the user syntaxes, so the compiled cache is rebuilt whenever one changes
+fn syntaxes_stamp(files: &[PathBuf]) -> Result<String, Box<dyn std::error::Error>> {
+    let mut hasher = DefaultHasher::new();
+    env!("CARGO_PKG_VERSION").hash(&mut hasher);
+    for file in files {
+        let metadata = std::fs::metadata(file)?;
+        file.hash(&mut hasher);
+        metadata.len().hash(&mut hasher);
+        metadata.modified()?.hash(&mut hasher);
+    }
+    Ok(format!("{:x}", hasher.finish()))
+}
+
+fn default_syntaxes() -> Result<SyntaxSet, Box<dyn std::error::Error>> {
+    Ok(from_uncompressed_data(include_bytes!(
+        "../assets/default_newlines.packdump"
+    ))?)
+}
+
+pub async fn load_syntaxes(
+    config_dir: &Path,
+    data_dir: &Path,
+) -> Result<SyntaxSet, Box<dyn std::error::Error>> {
+    let syntaxes_dir = config_dir.join("syntaxes");
+
+    let mut files = vec![];
+    if syntaxes_dir.is_dir() {
+        find_syntax_files(&syntaxes_dir, &mut files)?;
+    }
+
+    if files.is_empty() {
+        return default_syntaxes();
+    }
+
+    files.sort();
+
+    let stamp = syntaxes_stamp(&files)?;
+    let cache_path = data_dir.join("syntaxes.packdump");
+    let stamp_path = data_dir.join("syntaxes.stamp");
+
+    if cache_path.exists()
+        && tokio::fs::read_to_string(&stamp_path)
+            .await
+            .is_ok_and(|cached| cached == stamp)
+    {
+        if let Ok(ps) = from_uncompressed_dump_file(&cache_path) {
+            return Ok(ps);
+        }
+    }
+
+    let mut builder = default_syntaxes()?.into_builder();
+    if let Err(e) = builder.add_from_folder(&syntaxes_dir, true) {
+        println!(
+            "Error loading syntaxes from {}: {}",
+            syntaxes_dir.display(),
+            e
+        );
+        return default_syntaxes();
+    }
+
+    let ps = builder.build();
+
+    // Without the cache the syntaxes are only loaded again on the next start
+    let cached = match dump_to_uncompressed_file(&ps, &cache_path) {
+        Ok(()) => tokio::fs::write(&stamp_path, stamp)
+            .await
+            .map_err(Into::into),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = cached {
+        println!("Error caching syntaxes in {}: {}", data_dir.display(), e);
+    }
+
+    Ok(ps)
+}
+
+pub fn load_themes(config_dir: &Path) -> Result<ThemeSet, Box<dyn std::error::Error>> {
+    let mut ts = ThemeSet::load_defaults();
+
+    let themes_dir = config_dir.join("themes");
+    if !themes_dir.is_dir() {
+        return Ok(ts);
+    }
+
+    for path in ThemeSet::discover_theme_paths(&themes_dir)? {
+        match ThemeSet::get_theme(&path) {
+            Ok(theme) => {
+                if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+                    ts.themes.insert(name.to_string(), theme);
+                }
+            }
+            Err(e) => println!("Error loading theme {}: {}", path.display(), e),
+        }
+    }
+
+    Ok(ts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::temp_dir;
+
+    const SYNTAX: &str = "%YAML 1.2
+---
+name: Gptcli Test
+file_extensions: [gptclitest]
+scope: source.gptclitest
+contexts:
+  main:
+    - match: 'x'
+      scope: keyword
+";
+
+    #[tokio::test]
+    async fn user_syntaxes_load_when_they_cant_be_cached() {
+        let directory = temp_dir();
+        let syntaxes_dir = directory.path().join("syntaxes");
+        std::fs::create_dir(&syntaxes_dir).unwrap();
+        std::fs::write(syntaxes_dir.join("test.sublime-syntax"), SYNTAX).unwrap();
+        let data_dir = directory.path().join("missing");
+
+        let ps = load_syntaxes(directory.path(), &data_dir).await.unwrap();
+
+        assert!(ps.find_syntax_by_extension("gptclitest").is_some());
+        assert!(!data_dir.exists());
+    }
+}
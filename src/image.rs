@@ -0,0 +1,253 @@
+use std::path::{Path, PathBuf};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use crate::{
+    api,
+    config::{ImagesConfig, OpenAIConfig},
+    conversation::{ImageUrl, Part},
+};
+
+// The largest image the API accepts
+const MAX_IMAGE_SIZE: usize = 20 * 1024 * 1024;
+
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "webp"];
+
+// Detected from the file contents, since the extension may be missing or wrong
+fn mime_type(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some("image/jpeg")
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+fn is_image_path(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| IMAGE_EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+}
+
+// The @path mentions in a prompt which look like images
+pub fn mentioned_images(line: &str) -> Vec<&str> {
+    line.split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .filter(|path| is_image_path(path))
+        .collect()
+}
+
+pub struct Attachment {
+    pub part: Part,
+    // The path, type and size, to tell the user what was attached
+    pub description: String,
+    pub data: Vec<u8>,
+}
+
+pub async fn load_image(path: &str) -> Result<Attachment, Box<dyn std::error::Error>> {
+    let data = tokio::fs::read(path)
+        .await
+        .map_err(|e| format!("Error reading {}: {}", path, e))?;
+
+    if data.len() > MAX_IMAGE_SIZE {
+        return Err(format!(
+            "{} is {:.1} MB, images can be at most {} MB",
+            path,
+            data.len() as f64 / 1024.0 / 1024.0,
+            MAX_IMAGE_SIZE / 1024 / 1024
+        )
+        .into());
+    }
+
+    let mime_type = mime_type(&data)
+        .ok_or_else(|| format!("{} is not a PNG, JPEG, GIF or WebP image", path))?;
+
+    let part = Part::ImageUrl {
+        image_url: ImageUrl {
+            url: format!("data:{};base64,{}", mime_type, STANDARD.encode(&data)),
+        },
+    };
+
+    Ok(Attachment {
+        part,
+        description: format!("{} ({}, {} KB)", path, mime_type, data.len().div_ceil(1024)),
+        data,
+    })
+}
+
+// Options of an image generation, from [images] and --size, --quality and --n
+pub struct ImageRequest {
+    pub model: String,
+    pub size: String,
+    pub quality: String,
+    pub n: u32,
+}
+
+pub struct GeneratedImage {
+    pub data: Vec<u8>,
+    revised_prompt: Option<String>,
+}
+
+// Leading options followed by the prompt, e.g. "--size 1792x1024 --quality hd a lighthouse at dusk"
+pub fn parse_image_request(
+    config: &ImagesConfig,
+    input: &str,
+) -> Result<(ImageRequest, String), Box<dyn std::error::Error>> {
+    let mut request = ImageRequest {
+        model: config.model.clone(),
+        size: config.size.clone(),
+        quality: config.quality.clone(),
+        n: config.n,
+    };
+
+    let mut rest = input.trim();
+    while let Some(option) = rest.strip_prefix("--") {
+        let (name, after) = option
+            .split_once(' ')
+            .ok_or_else(|| format!("--{} needs a value", option))?;
+        let after = after.trim_start();
+        let (value, after) = after.split_once(' ').unwrap_or((after, ""));
+
+        match name {
+            "size" => request.size = value.to_string(),
+            "quality" => request.quality = value.to_string(),
+            "model" => request.model = value.to_string(),
+            "n" => request.n = value.parse().map_err(|e| format!("Invalid --n: {}", e))?,
+            _ => return Err(format!("Unknown option --{}", name).into()),
+        }
+
+        rest = after.trim_start();
+    }
+
+    if rest.is_empty() {
+        return Err("No prompt given".into());
+    }
+
+    Ok((request, rest.to_string()))
+}
+
+pub async fn generate_images(
+    client: &reqwest::Client,
+    headers: &reqwest::header::HeaderMap,
+    openai: &OpenAIConfig,
+    request: &ImageRequest,
+    prompt: &str,
+) -> Result<Vec<GeneratedImage>, Box<dyn std::error::Error>> {
+    let body = serde_json::json!({
+        "model": request.model,
+        "prompt": prompt,
+        "n": request.n,
+        "size": request.size,
+        "quality": request.quality,
+        "response_format": "b64_json",
+    });
+
+    let res = api::post_json(client, headers, openai, "images/generations", &body).await?;
+
+    let data = res["data"]
+        .as_array()
+        .ok_or("The response holds no images")?;
+    data.iter()
+        .map(|image| {
+            let b64_json = image["b64_json"]
+                .as_str()
+                .ok_or("The response holds no image data")?;
+            Ok(GeneratedImage {
+                data: STANDARD.decode(b64_json)?,
+                revised_prompt: image["revised_prompt"].as_str().map(str::to_string),
+            })
+        })
+        .collect()
+}
+
+// An iTXt chunk, which holds UTF-8 text unlike tEXt
+fn png_text_chunk(keyword: &str, text: &str) -> Vec<u8> {
+    let mut data = keyword.as_bytes().to_vec();
+    // Null separator, no compression, no language tag or translated keyword
+    data.extend_from_slice(&[0, 0, 0, 0, 0]);
+    data.extend_from_slice(text.as_bytes());
+
+    let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(b"iTXt");
+    chunk.extend_from_slice(&data);
+    chunk.extend_from_slice(&crc32fast::hash(&chunk[4..]).to_be_bytes());
+    chunk
+}
+
+// Adds text chunks right after the IHDR chunk, which must come first
+fn add_png_metadata(png: &[u8], metadata: &[(&str, &str)]) -> Vec<u8> {
+    if png.len() < 33 || !png.starts_with(b"\x89PNG\r\n\x1a\n") || &png[12..16] != b"IHDR" {
+        return png.to_vec();
+    }
+
+    let ihdr_end = 8 + 12 + u32::from_be_bytes([png[8], png[9], png[10], png[11]]) as usize;
+    let mut out = png[..ihdr_end].to_vec();
+    for (keyword, text) in metadata {
+        out.extend(png_text_chunk(keyword, text));
+    }
+    out.extend_from_slice(&png[ihdr_end..]);
+    out
+}
+
+// The first few words of the prompt, to recognize the files by
+pub fn file_name_slug(prompt: &str) -> String {
+    let slug = prompt
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect::<Vec<String>>()
+        .join("-");
+
+    match slug.char_indices().nth(40) {
+        Some((end, _)) => slug[..end].trim_end_matches('-').to_string(),
+        None => slug,
+    }
+}
+
+// Saves the images as PNGs with the prompt in their metadata, and returns their paths
+pub async fn save_images(
+    directory: &Path,
+    request: &ImageRequest,
+    prompt: &str,
+    images: &[GeneratedImage],
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    tokio::fs::create_dir_all(directory).await?;
+
+    let now = chrono::Local::now();
+    let name = format!(
+        "{}-{}",
+        now.format("%Y-%m-%d_%H-%M-%S"),
+        file_name_slug(prompt)
+    );
+    let created = now.to_rfc3339();
+
+    let mut paths = vec![];
+    for (i, image) in images.iter().enumerate() {
+        let path = if images.len() == 1 {
+            directory.join(format!("{}.png", name))
+        } else {
+            directory.join(format!("{}-{}.png", name, i + 1))
+        };
+
+        let mut metadata = vec![
+            ("Description", prompt),
+            ("Software", "gptcli"),
+            ("Source", request.model.as_str()),
+            ("Creation Time", created.as_str()),
+        ];
+        if let Some(revised_prompt) = &image.revised_prompt {
+            metadata.push(("Comment", revised_prompt.as_str()));
+        }
+
+        tokio::fs::write(&path, add_png_metadata(&image.data, &metadata)).await?;
+        paths.push(path);
+    }
+
+    Ok(paths)
+}
@@ -0,0 +1,89 @@
+use std::{
+    future::Future,
+    io::stdout,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crossterm::{
+    cursor, execute, style,
+    terminal::{disable_raw_mode, Clear, ClearType},
+};
+use tokio::sync::Notify;
+
+// Leaves the terminal as it was before the prompt, which may be gone by now
+fn restore_terminal() {
+    let _ = disable_raw_mode();
+    let _ = execute!(
+        stdout(),
+        cursor::Show,
+        style::ResetColor,
+        cursor::MoveToColumn(0),
+        Clear(ClearType::CurrentLine)
+    );
+}
+
+// Ctrl-C cancels the request in flight. The prompt reads Ctrl-C as a key instead, and exits
+pub struct Interrupt {
+    requesting: AtomicBool,
+    notify: Notify,
+}
+
+impl Interrupt {
+    pub fn install() -> Result<Arc<Interrupt>, Box<dyn std::error::Error>> {
+        let interrupt = Arc::new(Interrupt {
+            requesting: AtomicBool::new(false),
+            notify: Notify::new(),
+        });
+
+        // Only SIGINT, so closing the terminal or kill still exit while a request is running
+        let handler_interrupt = interrupt.clone();
+        ctrlc::set_handler(move || {
+            if handler_interrupt.requesting.load(Ordering::SeqCst) {
+                handler_interrupt.notify.notify_waiters();
+                return;
+            }
+
+            restore_terminal();
+            std::process::exit(0);
+        })?;
+
+        // SIGTERM and SIGHUP exit right away, with the usual 128 + signal number
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let mut terminate = signal(SignalKind::terminate())?;
+            let mut hangup = signal(SignalKind::hangup())?;
+            tokio::spawn(async move {
+                let code = tokio::select! {
+                    _ = terminate.recv() => 128 + 15,
+                    _ = hangup.recv() => 128 + 1,
+                };
+                restore_terminal();
+                std::process::exit(code);
+            });
+        }
+
+        Ok(interrupt)
+    }
+
+    // Runs the future until it completes, or returns None if Ctrl-C is pressed first
+    pub async fn cancellable<F: Future>(&self, future: F) -> Option<F::Output> {
+        let notified = self.notify.notified();
+        tokio::pin!(notified);
+        // Registers for notify_waiters before the handler can see requesting
+        notified.as_mut().enable();
+        self.requesting.store(true, Ordering::SeqCst);
+
+        let output = tokio::select! {
+            output = future => Some(output),
+            _ = notified => None,
+        };
+
+        self.requesting.store(false, Ordering::SeqCst);
+        output
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use crossterm::style::Stylize;
+
+use crate::config::{Config, OpenAIConfig};
+
+const KEYRING_SERVICE: &str = "gptcli";
+const KEYRING_USER: &str = "openai";
+
+pub enum KeySource {
+    Env,
+    Command(String),
+    Keyring,
+    File(PathBuf),
+}
+
+impl fmt::Display for KeySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeySource::Env => write!(f, "the OPENAI_API_KEY environment variable"),
+            KeySource::Command(command) => write!(f, "the output of `{}`", command),
+            KeySource::Keyring => write!(f, "the system keyring"),
+            KeySource::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+fn key_path(config_dir: &Path) -> PathBuf {
+    config_dir.join("openai.key")
+}
+
+fn keyring_entry() -> Option<keyring::Entry> {
+    keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER).ok()
+}
+
+async fn run_key_command(command: &str) -> Result<String, Box<dyn std::error::Error>> {
+    #[cfg(windows)]
+    let output = tokio::process::Command::new("cmd")
+        .args(["/C", command])
+        .output()
+        .await?;
+    #[cfg(not(windows))]
+    let output = tokio::process::Command::new("sh")
+        .args(["-c", command])
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "`{}` exited with {}: {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
+
+// The key file must only be readable by its owner
+#[cfg(unix)]
+async fn restrict_permissions(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = tokio::fs::metadata(path).await?.permissions().mode();
+    if mode & 0o077 != 0 {
+        tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).await?;
+        println!(
+            "Restricted the permissions of {} to 0600 (were {:o})",
+            path.display(),
+            mode & 0o777
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn restrict_permissions(_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    Ok(())
+}
+
+// Looks the key up in order: environment, key command, system keyring, key file
+pub async fn resolve_key(
+    config_dir: &Path,
+    config: &Config,
+) -> Result<Option<(String, KeySource)>, Box<dyn std::error::Error>> {
+    if let Ok(openai_key) = std::env::var("OPENAI_API_KEY") {
+        if !openai_key.trim().is_empty() {
+            return Ok(Some((openai_key.trim().to_string(), KeySource::Env)));
+        }
+    }
+
+    if let Some(command) = &config.openai.api_key_command {
+        match run_key_command(command).await {
+            Ok(openai_key) if !openai_key.is_empty() => {
+                return Ok(Some((openai_key, KeySource::Command(command.clone()))));
+            }
+            Ok(_) => println!("`{}` did not output an API key", command.clone().bold()),
+            Err(e) => println!("Error running API key command: {}", e),
+        }
+    }
+
+    if let Some(entry) = keyring_entry() {
+        if let Ok(openai_key) = entry.get_password() {
+            return Ok(Some((openai_key.trim().to_string(), KeySource::Keyring)));
+        }
+    }
+
+    let path = key_path(config_dir);
+    if path.exists() {
+        restrict_permissions(&path).await?;
+
+        let openai_key = tokio::fs::read_to_string(&path).await?;
+        let openai_key = openai_key.trim_end_matches(['\r', '\n']).to_string();
+        if !openai_key.is_empty() {
+            return Ok(Some((openai_key, KeySource::File(path))));
+        }
+    }
+
+    Ok(None)
+}
+
+// Stores the key in the system keyring, falling back to a 0600 key file
+pub async fn store_key(
+    config_dir: &Path,
+    openai_key: &str,
+) -> Result<KeySource, Box<dyn std::error::Error>> {
+    if let Some(entry) = keyring_entry() {
+        if entry.set_password(openai_key).is_ok() {
+            return Ok(KeySource::Keyring);
+        }
+    }
+
+    let path = key_path(config_dir);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&path)?;
+        std::io::Write::write_all(&mut file, openai_key.as_bytes())?;
+    }
+    #[cfg(not(unix))]
+    tokio::fs::write(&path, openai_key).await?;
+
+    restrict_permissions(&path).await?;
+
+    Ok(KeySource::File(path))
+}
+
+pub async fn clear_key(config_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(entry) = keyring_entry() {
+        match entry.delete_password() {
+            Ok(()) => println!("Removed the OpenAI API key from the system keyring"),
+            // No entry, or no keyring on this system at all
+            Err(keyring::Error::NoEntry) | Err(keyring::Error::PlatformFailure(_)) => {}
+            Err(e) => println!("Error removing the key from the system keyring: {}", e),
+        }
+    }
+
+    let path = key_path(config_dir);
+    if path.exists() {
+        tokio::fs::remove_file(&path).await?;
+        println!("Removed {}", path.display());
+    }
+
+    Ok(())
+}
+
+pub async fn validate_key(
+    client: &reqwest::Client,
+    openai: &OpenAIConfig,
+    openai_key: &str,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let res = client
+        .get(openai.url("models"))
+        .bearer_auth(openai_key)
+        .send()
+        .await?;
+
+    Ok(res.status() == 200)
+}
+
+pub async fn prompt_key(
+    client: &reqwest::Client,
+    openai: &OpenAIConfig,
+) -> Result<String, Box<dyn std::error::Error>> {
+    loop {
+        let openai_key = dialoguer::Password::new()
+            .with_prompt("Enter your OpenAI API key")
+            .interact()?;
+        let openai_key = openai_key.trim().to_string();
+
+        if openai_key.is_empty() {
+            continue;
+        }
+
+        if !validate_key(client, openai, &openai_key).await? {
+            println!("Invalid OpenAI API key");
+            continue;
+        }
+
+        return Ok(openai_key);
+    }
+}
+
+// gptcli key set/clear/show-source
+pub async fn key_command(
+    client: &reqwest::Client,
+    config_dir: &Path,
+    config: &Config,
+    subcommand: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match subcommand {
+        "set" => {
+            let openai_key = prompt_key(client, &config.openai).await?;
+            let source = store_key(config_dir, &openai_key).await?;
+            println!("OpenAI key has been stored in {}", source);
+        }
+        "clear" => {
+            clear_key(config_dir).await?;
+
+            if let Some((_, source)) = resolve_key(config_dir, config).await? {
+                println!("An OpenAI API key is still provided by {}", source);
+            }
+        }
+        "show-source" => match resolve_key(config_dir, config).await? {
+            Some((_, source)) => println!("The OpenAI API key is read from {}", source),
+            None => println!("No OpenAI API key is configured"),
+        },
+        _ => {
+            println!("Usage: gptcli key <set|clear|show-source>");
+        }
+    }
+
+    Ok(())
+}
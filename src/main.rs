@@ -1,50 +1,73 @@
+mod api;
+mod audio;
+mod commit;
+mod compact;
 mod config;
+mod conversation;
+mod decode;
+mod display;
+mod editor;
+mod embeddings;
+mod helper;
+mod highlight;
+mod image;
+mod interrupt;
+mod key;
+mod models;
+mod network;
+mod progress;
+mod rag;
+mod search;
+mod shell;
 mod spinners;
+mod templates;
+#[cfg(test)]
+mod test_server;
 
 use std::{
     collections::HashMap,
-    fs::File,
-    io::{stdout, BufRead, BufReader, Write},
+    io::{stdout, Write},
     panic,
     path::{self, Path},
-    sync::{Arc, Mutex},
+    sync::atomic::Ordering,
 };
 
-use config::Config;
-use rand::seq::SliceRandom;
-use spinners::{get_spinners, Spinner};
-use syntect::highlighting::{Style as HStyle, ThemeSet};
-use syntect::parsing::SyntaxSet;
-use syntect::util::{as_24_bit_terminal_escaped, LinesWithEndings};
-use syntect::{dumps::from_uncompressed_data, easy::HighlightLines};
+use conversation::{Conversation, Message, Part};
+use display::InlineImages;
+use helper::{EditorKeyHandler, ReplHelper};
+use highlight::Highlighter;
+use interrupt::Interrupt;
+use progress::Progress;
 
+#[cfg(windows)]
+use crossterm::terminal::enable_raw_mode;
 use crossterm::{
     cursor, execute,
-    style::{self, Color, Stylize},
-    terminal::{disable_raw_mode, enable_raw_mode, Clear, ClearType, self},
+    style::{self, Stylize},
+    terminal::{self, disable_raw_mode, Clear, ClearType},
 };
 
 use directories::ProjectDirs;
-use regex::Regex;
-use rustyline::{config::Configurer, error::ReadlineError, ColorMode, Editor};
-use serde::{Deserialize, Serialize};
-use tokio::{io::AsyncWriteExt, main};
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Message {
-    role: String,
-    content: String,
-}
+#[cfg(windows)]
+use rustyline::{config::Configurer, ColorMode};
+use rustyline::{error::ReadlineError, history::FileHistory, Editor, EventHandler, KeyEvent};
+use serde::Deserialize;
+use tokio::main;
 
+#[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 struct ChatCompletion {
     id: String,
     object: String,
     created: u64,
+    // The exact version which answered, e.g. gpt-4o-2024-08-06 for gpt-4o
+    #[serde(default)]
+    model: String,
     choices: Vec<Choice>,
     usage: Usage,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 struct Choice {
     index: u32,
@@ -52,6 +75,7 @@ struct Choice {
     finish_reason: String,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 struct Usage {
     prompt_tokens: u32,
@@ -61,16 +85,8 @@ struct Usage {
 
 #[main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let code_re = Regex::new(r#"```(?P<language>\w+)(?:\r?\n|\r)(?P<code>[\s\S]*?)\r?\n```"#)?;
-    let tiny_code_re = Regex::new(r#"`(?P<tinycode>[^`]+)`"#)?;
-
     let args = std::env::args().collect::<Vec<String>>();
 
-    let ps: SyntaxSet =
-        from_uncompressed_data(include_bytes!("../assets/default_newlines.packdump"))?;
-    let ts = ThemeSet::load_defaults();
-
-    let client = reqwest::Client::new();
     let mut headers = reqwest::header::HeaderMap::new();
     headers.append("Content-Type", "application/json".parse()?);
 
@@ -86,129 +102,112 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         tokio::fs::create_dir_all(&data_dir).await?;
     }
 
-    let mut config = {
-        if !config_dir.join("config.toml").exists() {
-            let config = config::create_config(config_dir.to_str().unwrap()).await?;
-            tokio::fs::write(config_dir.join("config.toml"), toml::to_string(&config)?).await?;
-            config
-        } else {
-            let res =
-                toml::from_str(&tokio::fs::read_to_string(config_dir.join("config.toml")).await?);
-            if let Err(e) = res {
-                println!("Error parsing config: {}", e);
-                let config = config::create_config(config_dir.to_str().unwrap()).await?;
-
-                // Move config.toml to config.toml.bak
-                tokio::fs::rename(
-                    config_dir.join("config.toml"),
-                    config_dir.join("config.toml.bak"),
-                )
-                .await?;
-                tokio::fs::write(config_dir.join("config.toml"), toml::to_string(&config)?).await?;
-                config
-            } else {
-                res?
-            }
-        }
-    };
+    let mut config = config::load_config(config_dir.to_str().unwrap()).await?;
 
-    if !config_dir.join("spinners.json").exists() {
-        let spinners = get_spinners().await?;
-        tokio::fs::write(
-            config_dir.join("spinners.json"),
-            serde_json::to_string(&spinners)?,
+    let mut client = network::build_client(&config_dir, &config.network).await?;
+
+    if args.len() > 1 && args[1] == "key" {
+        key::key_command(
+            &client,
+            &config_dir,
+            &config,
+            args.get(2).map_or("", |subcommand| subcommand.as_str()),
         )
         .await?;
+        return Ok(());
     }
 
-    let spinners: HashMap<String, Spinner> =
-        serde_json::from_str(&tokio::fs::read_to_string(config_dir.join("spinners.json")).await?)?;
+    // The local embedder needs no API key, so index and search don't ask for one with it
+    let embeddings_subcommand = args.len() > 1 && (args[1] == "index" || args[1] == "search");
+    if embeddings_subcommand && config.embeddings.embedder != "api" {
+        embeddings_command(&client, &headers, &config, &data_dir, &args[1..]).await;
+        return Ok(());
+    }
 
-    if !config_dir.join("openai.key").exists() {
-        loop {
-            let openai_key = dialoguer::Password::new()
-                .with_prompt("Enter your OpenAI API key")
-                .interact()?;
+    let mut highlighter = Highlighter::new(
+        highlight::load_syntaxes(&config_dir, &data_dir).await?,
+        highlight::load_themes(&config_dir)?,
+        &config.app.syntax_theme,
+    )?;
 
-            if openai_key.is_empty() {
-                // Move cursor up
-                execute!(stdout(), cursor::MoveUp(1))?;
-                continue;
-            }
+    let spinners = spinners::load_spinners(&config_dir).await?;
 
+    match key::resolve_key(&config_dir, &config).await? {
+        Some((openai_key, _)) => {
             headers.append("Authorization", format!("Bearer {}", openai_key).parse()?);
+        }
+        None => {
+            let openai_key = key::prompt_key(&client, &config.openai).await?;
 
-            let res = client
-                .get("https://api.openai.com/v1/models")
-                .headers(headers.clone())
-                .send()
-                .await?;
-
-            if res.status() != 200 {
-                println!("Invalid OpenAI API key");
-                headers.remove("Authorization");
-                continue;
-            }
-
-            if !config.app.notify_save {
-                break;
-            }
-
-            let save_confirm = dialoguer::Confirm::new()
-                .with_prompt("Save OpenAI API key?")
-                .interact()?;
+            headers.append("Authorization", format!("Bearer {}", openai_key).parse()?);
 
-            if !save_confirm {
+            if config.app.notify_save {
                 let save_confirm = dialoguer::Confirm::new()
-                    .with_prompt(format!(
-                        "Ask again next time? (you can change this in {}/config.toml)",
-                        config_dir.display()
-                    ))
+                    .with_prompt("Save OpenAI API key?")
                     .interact()?;
-                if !save_confirm {
-                    break;
-                }
 
-                config.app.notify_save = false;
-
-                break;
+                if save_confirm {
+                    let source = key::store_key(&config_dir, &openai_key).await?;
+
+                    println!(
+                        "OpenAI key has been stored in {}. Run `gptcli key clear` to remove it.",
+                        source
+                    );
+                } else {
+                    let ask_again = dialoguer::Confirm::new()
+                        .with_prompt(format!(
+                            "Ask again next time? (you can change this in {}/config.toml)",
+                            config_dir.display()
+                        ))
+                        .interact()?;
+
+                    config.app.notify_save = ask_again;
+                }
             }
-
-            tokio::fs::write(config_dir.join("openai.key"), openai_key.clone()).await?;
-
-            println!(
-                "OpenAI key has been stored in {}. Delete it if you wish.",
-                config_dir.join("openai.key").display()
-            );
-
-            break;
         }
-    } else {
-        let openai_key = tokio::fs::read_to_string(config_dir.join("openai.key")).await?;
+    }
 
-        headers.append("Authorization", format!("Bearer {}", openai_key).parse()?);
+    let mut rl = Editor::<ReplHelper, FileHistory>::with_config(
+        rustyline::Config::builder()
+            .bracketed_paste(true)
+            .max_history_size(config.app.history_size.max(1))?
+            .history_ignore_dups(true)?
+            .history_ignore_space(config.app.history_ignore_space)
+            .build(),
+    )?;
+
+    // A history_size of 0 keeps the history for this session only
+    let history_path = (config.app.history_size > 0).then(|| data_dir.join("history.txt"));
+    if let Some(history_path) = &history_path {
+        if history_path.exists() {
+            if let Err(e) = rl.load_history(history_path) {
+                println!("Error loading history: {}", e);
+            }
+        }
     }
 
-    let mut rl = Editor::<(), _>::new()?;
+    let helper = ReplHelper::new(
+        highlighter.ts.themes.keys().cloned().collect(),
+        config::keys(),
+        models::cached_ids(&data_dir, &config.openai).await,
+        templates::template_names(&config_dir),
+    );
+    let open_editor = helper.open_editor.clone();
+    rl.set_helper(Some(helper));
+    // Alt-E opens the current input in $EDITOR
+    rl.bind_sequence(
+        KeyEvent::alt('e'),
+        EventHandler::Conditional(Box::new(EditorKeyHandler(open_editor.clone()))),
+    );
     #[cfg(windows)]
     {
         rl.set_color_mode(ColorMode::Forced);
         enable_raw_mode()?;
     }
 
-    let mut messages: Vec<Message> = vec![];
-
     let data_dir_c = data_dir.clone();
 
-    ctrlc::set_handler(move || {
-        disable_raw_mode().unwrap();
-        execute!(stdout(), cursor::Show).unwrap();
-        execute!(stdout(), style::ResetColor).unwrap();
-        execute!(stdout(), cursor::MoveToColumn(0)).unwrap();
-        execute!(stdout(), Clear(ClearType::CurrentLine)).unwrap();
-        execute!(stdout(), style::ResetColor).unwrap();
-        std::process::exit(0);
-    })?;
+    let interrupt = Interrupt::install()?;
 
     panic::set_hook(Box::new(move |panic_info| {
         let mut file = std::fs::File::create(data_dir_c.join("panic.log")).unwrap();
@@ -220,33 +219,117 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         );
     }));
 
-    messages.push(Message {
-        role: "user".to_string(),
-        content: config.app.prompt.clone(),
-    });
+    let mut conversation = Conversation::new(&config.app.prompt);
+
+    let mut progress = Progress::new(&spinners, &config.app);
+    let mut inline_images = InlineImages::new(&config.app);
 
-    let spinner_values = spinners.values().collect::<Vec<&Spinner>>();
+    // Original values of the keys changed with /set, which are only persisted by /save
+    let mut overrides: HashMap<String, serde_json::Value> = HashMap::new();
+
+    // Attached with /image, and sent with the next message
+    let mut pending_images: Vec<Part> = vec![];
+
+    // The document index turned on with /rag, and what was retrieved from it for the last message
+    let mut rag_index: Option<(String, rag::DocIndex)> = None;
+    let mut sources: Vec<rag::Source> = vec![];
+
+    if args.len() > 1 && args[1] == "image" {
+        match imagine(
+            &client,
+            &headers,
+            &config,
+            &data_dir,
+            &progress,
+            &interrupt,
+            &args[2..].join(" "),
+        )
+        .await
+        {
+            Ok(saved) => show_saved_images(saved, &inline_images),
+            Err(e) => println!("{}", e),
+        }
+        return Ok(());
+    }
+
+    if embeddings_subcommand {
+        embeddings_command(&client, &headers, &config, &data_dir, &args[1..]).await;
+        return Ok(());
+    }
+
+    if args.len() > 1 && args[1] == "commit" {
+        if let Err(e) = commit::commit_command(
+            &client,
+            &headers,
+            &config,
+            &progress,
+            &interrupt,
+            &args[2..],
+        )
+        .await
+        {
+            println!("{}", e);
+        }
+        return Ok(());
+    }
+
+    if args.len() > 1 && args[1] == "cmd" {
+        if let Err(e) = shell::cmd_command(
+            &client,
+            &headers,
+            &config,
+            &highlighter,
+            &progress,
+            &interrupt,
+            &args[2..],
+        )
+        .await
+        {
+            println!("{}", e);
+        }
+        return Ok(());
+    }
 
     if args.len() > 1 {
         let mut args = args;
         args.remove(0);
 
-        if args[0] != "c" {
-            let line = args.join(" ");
-            rl.add_history_entry(line.as_str())?;
-            chat_completion(
-                &client,
-                &headers,
-                &mut messages,
-                &spinner_values,
-                &config,
-                &line,
-                &ps,
-                &ts,
-                &code_re,
-                &tiny_code_re,
-            )
-            .await?;
+        let line = match args[0].as_str() {
+            "c" => None,
+            "-t" => match args.get(1) {
+                Some(name) => match templates::render(&config_dir, name, &args[2..]).await {
+                    Ok(line) => Some(line),
+                    Err(e) => {
+                        println!("{}", e);
+                        None
+                    }
+                },
+                None => {
+                    println!("Usage: gptcli -t <template> [key=value ...]");
+                    None
+                }
+            },
+            _ => Some(args.join(" ")),
+        };
+
+        if let Some(line) = line {
+            add_history_entry(&mut rl, &line, history_path.as_deref());
+            if attach_mentioned_images(&line, &mut pending_images, &inline_images).await {
+                conversation.push(Message::user_with_images(
+                    &line,
+                    std::mem::take(&mut pending_images),
+                ));
+                chat_completion(
+                    &client,
+                    &headers,
+                    &mut conversation,
+                    &progress,
+                    &config,
+                    &highlighter,
+                    &interrupt,
+                )
+                .await;
+            }
         }
     }
 
@@ -255,19 +338,40 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let readline = rl.readline(">> ");
         match readline {
             Ok(line) => {
+                let mut line = if open_editor.swap(false, Ordering::SeqCst) {
+                    let line = match editor::edit(&line).await {
+                        Ok(line) => line,
+                        Err(e) => {
+                            println!("Error editing the message: {}", e);
+                            continue;
+                        }
+                    };
+                    if line.is_empty() {
+                        println!("Nothing to send");
+                        continue;
+                    }
+                    line
+                } else {
+                    helper::normalize_input(&line)
+                };
+
                 if line.is_empty() {
                     execute!(stdout(), cursor::MoveUp(1))?;
                     continue;
                 }
 
-                if line.starts_with("/") {
-                    match line.as_str() {
+                if line.starts_with('/') {
+                    let (command, arg) = line
+                        .split_once(' ')
+                        .map_or((line.as_str(), ""), |(command, arg)| (command, arg.trim()));
+
+                    // Set by commands which send a prompt, like /edit
+                    let mut prompt = None;
+
+                    match command {
                         "/clear" => {
-                            messages.clear();
-                            messages.push(Message {
-                                role: "user".to_string(),
-                                content: config.app.prompt.clone(),
-                            });
+                            conversation.clear(&config.app.prompt);
+                            pending_images.clear();
 
                             println!("Conversation history has been cleared");
                         }
@@ -276,11 +380,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                                 .with_prompt("Enter new prompt")
                                 .interact()?;
                             config.app.prompt = prompt;
-                            messages.clear();
-                            messages.push(Message {
-                                role: "user".to_string(),
-                                content: config.app.prompt.clone(),
-                            });
+                            conversation.clear(&config.app.prompt);
                         }
                         "/save" => {
                             let save_confirm = dialoguer::Confirm::new()
@@ -292,37 +392,520 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                                     &config,
                                 )
                                 .await?;
+                                overrides.clear();
+                            }
+                        }
+                        "/set" => {
+                            let Some((key, value)) = arg.split_once(' ') else {
+                                println!("Usage: /set <section.key> <value>");
+                                continue;
+                            };
+
+                            let original = config::get_value(&config, key);
+
+                            if let Err(e) = config::set_value(&mut config, key, value) {
+                                println!("Invalid value for {}: {}", key.bold().red(), e);
+                                continue;
+                            }
+
+                            if key == "app.syntax_theme"
+                                && !highlighter.ts.themes.contains_key(&config.app.syntax_theme)
+                            {
+                                println!(
+                                    "Unknown syntax theme: {}",
+                                    config.app.syntax_theme.clone().bold().red()
+                                );
+                                config.app.syntax_theme = highlighter.theme.clone();
+                                continue;
+                            }
+
+                            if key.starts_with("network.") {
+                                match network::build_client(&config_dir, &config.network).await {
+                                    Ok(network_client) => client = network_client,
+                                    Err(e) => {
+                                        println!("Invalid value for {}: {}", key.bold().red(), e);
+                                        // The value as it was, not parsed again from text
+                                        if let Some(original) = original {
+                                            if let Err(e) =
+                                                config::set_json_value(&mut config, key, original)
+                                            {
+                                                println!("Error restoring {}: {}", key, e);
+                                            }
+                                        }
+                                        continue;
+                                    }
+                                }
+                            }
+
+                            if let Some(original) = original {
+                                overrides.entry(key.to_string()).or_insert(original);
+                            }
+
+                            highlighter.theme = config.app.syntax_theme.clone();
+                            if key.starts_with("app.") {
+                                progress = Progress::new(&spinners, &config.app);
+                                inline_images = InlineImages::new(&config.app);
+                            }
+                            if key == "app.prompt" {
+                                conversation.set_prompt(&config.app.prompt);
+                            }
+
+                            println!(
+                                "{} = {} (use /save to keep it)",
+                                key,
+                                config::get_value(&config, key).unwrap_or_default()
+                            );
+                        }
+                        "/get" => {
+                            if arg.is_empty() {
+                                for key in config::keys() {
+                                    let value =
+                                        config::get_value(&config, &key).unwrap_or_default();
+                                    println!("{} = {}", key, value);
+                                }
+                                continue;
+                            }
+
+                            match config::get_value(&config, arg) {
+                                Some(value) => println!("{} = {}", arg, value),
+                                None => println!("Unknown config key: {}", arg.bold().red()),
+                            }
+                        }
+                        "/config" => {
+                            println!("{}", toml::to_string(&config)?);
+                        }
+                        "/theme" => {
+                            let theme = if arg.is_empty() {
+                                highlighter.pick_theme()?
+                            } else if highlighter.ts.themes.contains_key(arg) {
+                                Some(arg.to_string())
+                            } else {
+                                println!("Unknown syntax theme: {}", arg.bold().red());
+                                None
+                            };
+
+                            if let Some(theme) = theme {
+                                highlighter.theme = theme.clone();
+                                config.app.syntax_theme = theme;
+                                overrides.remove("app.syntax_theme");
+                                config::save_config_except(
+                                    config_dir.display().to_string().as_str(),
+                                    &config,
+                                    &overrides,
+                                )
+                                .await?;
+
+                                println!(
+                                    "Syntax theme set to {}",
+                                    config.app.syntax_theme.clone().bold()
+                                );
+                            }
+                        }
+                        "/edit" if arg.is_empty() => {
+                            let text = match editor::edit("").await {
+                                Ok(text) => text,
+                                Err(e) => {
+                                    println!("Error editing the message: {}", e);
+                                    continue;
+                                }
+                            };
+                            if text.is_empty() {
+                                println!("Nothing to send");
+                            } else {
+                                prompt = Some(text);
+                            }
+                        }
+                        "/edit" => {
+                            let Some(index) = arg
+                                .parse::<isize>()
+                                .ok()
+                                .and_then(|n| conversation.user_message_index(n))
+                            else {
+                                match conversation.user_message_count() {
+                                    0 => println!("No messages to edit yet"),
+                                    count => println!(
+                                        "Usage: /edit <n>, where n is between 1 and {} (or negative to count from the end)",
+                                        count
+                                    ),
+                                }
+                                continue;
+                            };
+
+                            let content = conversation.messages()[index].content.clone();
+                            let text = match editor::edit(&content.text()).await {
+                                Ok(text) => text,
+                                Err(e) => {
+                                    println!("Error editing the message: {}", e);
+                                    continue;
+                                }
+                            };
+                            if text.is_empty() {
+                                println!("Nothing to send");
+                            } else {
+                                // The old message and everything after it stay in another branch
+                                conversation.rewind(index);
+                                pending_images.extend(content.images());
+                                prompt = Some(text);
+                            }
+                        }
+                        "/image" => {
+                            if arg.is_empty() {
+                                println!(
+                                    "Usage: /image <path>, {} image(s) attached to the next message",
+                                    pending_images.len()
+                                );
+                                continue;
+                            }
+
+                            match image::load_image(arg).await {
+                                Ok(attachment) => {
+                                    println!(
+                                        "Attached {} to the next message",
+                                        attachment.description
+                                    );
+                                    inline_images.show(&attachment.data);
+                                    pending_images.push(attachment.part);
+                                }
+                                Err(e) => println!("{}", e),
+                            }
+                        }
+                        "/imagine" => {
+                            match imagine(
+                                &client,
+                                &headers,
+                                &config,
+                                &data_dir,
+                                &progress,
+                                &interrupt,
+                                arg,
+                            )
+                            .await
+                            {
+                                Ok(saved) => show_saved_images(saved, &inline_images),
+                                Err(e) => println!("{}", e),
+                            }
+                        }
+                        "/transcribe" => {
+                            if arg.is_empty() {
+                                println!("Usage: /transcribe <audio file>");
+                                continue;
+                            }
+
+                            let transcription = audio::transcribe(
+                                &client,
+                                &headers,
+                                &config.openai,
+                                &config.audio,
+                                arg,
+                            );
+                            match progress.run(&interrupt, transcription).await {
+                                Some(Ok(transcript)) => {
+                                    println!("{}\n", transcript);
+                                    // The next messages can ask about it
+                                    conversation.push(Message::system(&format!(
+                                        "Transcript of {}:\n\n{}",
+                                        arg, transcript
+                                    )));
+                                    println!("Added the transcript to the conversation");
+                                }
+                                Some(Err(e)) => println!("{}", e),
+                                None => println!("Transcription cancelled"),
+                            }
+                        }
+                        "/speak" => {
+                            let Some(answer) = conversation
+                                .messages()
+                                .iter()
+                                .rev()
+                                .find(|message| message.role == "assistant")
+                                .map(|message| message.content.text())
+                            else {
+                                println!("No answer to speak yet");
+                                continue;
+                            };
+
+                            match speak(
+                                &client,
+                                &headers,
+                                &config,
+                                &data_dir,
+                                &progress,
+                                &interrupt,
+                                &answer,
+                            )
+                            .await
+                            {
+                                Ok(Some(path)) => println!("Saved {}", path.display()),
+                                Ok(None) => println!("Speech cancelled"),
+                                Err(e) => println!("{}", e),
+                            }
+                        }
+                        "/models" => {
+                            let (refresh, filter) = match arg.strip_prefix("--refresh") {
+                                Some(filter) => (true, filter.trim()),
+                                None => (false, arg),
+                            };
+
+                            let models = match models::list_models(
+                                &client,
+                                &headers,
+                                &config.openai,
+                                &data_dir,
+                                refresh,
+                            )
+                            .await
+                            {
+                                Ok(models) => models,
+                                Err(e) => {
+                                    println!("Error listing the models: {}", e);
+                                    continue;
+                                }
+                            };
+                            if let Some(helper) = rl.helper_mut() {
+                                helper.models = models.iter().map(|model| model.id.clone()).collect();
+                            }
+
+                            models::print_models(&models, filter, &config.openai.model);
+                        }
+                        "/model" => {
+                            if arg.is_empty() {
+                                println!(
+                                    "Using {}, switch with /model <id> (see /models)",
+                                    config.openai.model.clone().bold()
+                                );
+                                continue;
+                            }
+
+                            match models::list_models(
+                                &client,
+                                &headers,
+                                &config.openai,
+                                &data_dir,
+                                false,
+                            )
+                            .await
+                            {
+                                Ok(models) if !models.iter().any(|model| model.id == arg) => {
+                                    println!(
+                                        "Unknown model {}, see /models (or /models --refresh if it is new)",
+                                        arg.bold().red()
+                                    );
+                                    continue;
+                                }
+                                Ok(_) => {}
+                                // Switching still works with a server which doesn't list its models
+                                Err(e) => println!("Couldn't check the model list: {}", e),
+                            }
+
+                            let original = config::get_value(&config, "openai.model");
+                            config.openai.model = arg.to_string();
+                            if let Some(original) = original {
+                                overrides.entry("openai.model".to_string()).or_insert(original);
+                            }
+                            println!(
+                                "Switched to {} for this session (use /save to keep it)",
+                                config.openai.model.clone().bold()
+                            );
+                        }
+                        "/compact" => {
+                            if let Err(e) = compact::compact(
+                                &client,
+                                &headers,
+                                &config,
+                                &mut conversation,
+                                &progress,
+                                &interrupt,
+                                true,
+                            )
+                            .await
+                            {
+                                println!("Error compacting the conversation: {}", e);
+                            }
+                        }
+                        "/t" => {
+                            if arg.is_empty() {
+                                templates::print_templates(&config_dir).await;
+                                continue;
+                            }
+
+                            let rendered = match templates::split_args(arg) {
+                                Ok(args) => {
+                                    templates::render(&config_dir, &args[0], &args[1..]).await
+                                }
+                                Err(e) => Err(e),
+                            };
+                            match rendered {
+                                Ok(text) if text.is_empty() => println!("The template is empty"),
+                                Ok(text) => prompt = Some(text),
+                                Err(e) => println!("{}", e),
+                            }
+                        }
+                        "/retry" => {
+                            if conversation.retry() {
+                                sources = retrieve_sources(
+                                    &client,
+                                    &headers,
+                                    &config,
+                                    rag_index.as_ref(),
+                                    &mut conversation,
+                                )
+                                .await;
+                                if chat_completion(
+                                    &client,
+                                    &headers,
+                                    &mut conversation,
+                                    &progress,
+                                    &config,
+                                    &highlighter,
+                                    &interrupt,
+                                )
+                                .await
+                                {
+                                    show_sources(rag_index.as_ref(), &sources, &conversation);
+                                }
+                            } else {
+                                println!("Nothing to retry");
                             }
                         }
+                        "/undo" => {
+                            if conversation.undo() {
+                                println!("Removed the last exchange (it is still in /branches)");
+                            } else {
+                                println!("Nothing to undo");
+                            }
+                        }
+                        "/branches" => {
+                            println!("{}", conversation.tree());
+                        }
+                        "/checkout" => {
+                            match arg.parse::<usize>() {
+                                Ok(n) if conversation.checkout(n) => {
+                                    println!("{}", conversation.tree());
+                                }
+                                _ => println!(
+                                    "Usage: /checkout <n>, where n is a branch number between 1 and {} (see /branches)",
+                                    conversation.branch_count()
+                                ),
+                            }
+                        }
+                        "/fork" => {
+                            if config.app.save_conversation {
+                                save_log(&client, &headers, &config, &data_dir, &conversation)
+                                    .await?;
+                            }
+                            conversation = conversation.fork();
+                            println!("Continuing the current branch in a new session");
+                        }
+                        "/rag" => match arg.split_once(' ').unwrap_or((arg, "")) {
+                            ("on", name) if !name.is_empty() => {
+                                let loaded = match embeddings::Embedder::new(
+                                    &client,
+                                    &headers,
+                                    &config.openai,
+                                    &config.embeddings,
+                                ) {
+                                    Ok(embedder) => {
+                                        rag::load_index(&data_dir, name.trim(), &embedder).await
+                                    }
+                                    Err(e) => Err(e),
+                                };
+
+                                match loaded {
+                                    Ok(index) => {
+                                        println!(
+                                            "Each message is now sent with the {} most relevant of {} chunks from {}",
+                                            config.rag.top_k,
+                                            index.chunk_count(),
+                                            index.root.display()
+                                        );
+                                        rag_index = Some((name.trim().to_string(), index));
+                                    }
+                                    Err(e) => println!("{}", e),
+                                }
+                            }
+                            ("off", "") => {
+                                rag_index = None;
+                                sources.clear();
+                                conversation.set_context(None);
+                                println!("Messages are sent without documents again");
+                            }
+                            ("", "") => {
+                                if let Some((name, index)) = &rag_index {
+                                    println!(
+                                        "Using {} ({})",
+                                        name.clone().bold(),
+                                        index.root.display()
+                                    );
+                                }
+                                match rag::index_names(&data_dir).as_slice() {
+                                    [] => println!(
+                                        "No document indexes yet, create one with gptcli index <dir>"
+                                    ),
+                                    names => println!("Indexes: {}", names.join(", ")),
+                                }
+                            }
+                            _ => println!("Usage: /rag on <index>, /rag off, or /rag to list the indexes"),
+                        },
                         "/exit" => {
                             break;
                         }
                         _ => {
                             println!(
                                 "Unknown command: {}",
-                                line.strip_prefix("/").unwrap().bold().red()
+                                line.strip_prefix('/').unwrap().bold().red()
                             );
                         }
                     }
 
-                    continue;
+                    match prompt {
+                        Some(prompt) => line = prompt,
+                        None => continue,
+                    }
                 }
 
-                rl.add_history_entry(line.as_str())?;
+                add_history_entry(&mut rl, &line, history_path.as_deref());
+                if !attach_mentioned_images(&line, &mut pending_images, &inline_images).await {
+                    continue;
+                }
+                conversation.push(Message::user_with_images(
+                    &line,
+                    std::mem::take(&mut pending_images),
+                ));
+                if let Err(e) = compact::compact(
+                    &client,
+                    &headers,
+                    &config,
+                    &mut conversation,
+                    &progress,
+                    &interrupt,
+                    false,
+                )
+                .await
+                {
+                    println!("Error compacting the conversation, sending it whole: {}", e);
+                }
+                sources = retrieve_sources(
+                    &client,
+                    &headers,
+                    &config,
+                    rag_index.as_ref(),
+                    &mut conversation,
+                )
+                .await;
 
-                chat_completion(
+                if chat_completion(
                     &client,
                     &headers,
-                    &mut messages,
-                    &spinner_values,
+                    &mut conversation,
+                    &progress,
                     &config,
-                    &line,
-                    &ps,
-                    &ts,
-                    &code_re,
-                    &tiny_code_re,
+                    &highlighter,
+                    &interrupt,
                 )
-                .await?;
+                .await
+                {
+                    show_sources(rag_index.as_ref(), &sources, &conversation);
+                }
             }
             Err(ReadlineError::Interrupted) => {
                 println!();
@@ -348,255 +931,327 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     #[cfg(windows)]
     disable_raw_mode()?;
 
-    config::save_config(&config_dir.display().to_string().as_str(), &config).await?;
-
-    println!("Saved config!");
-
-    if config.app.save_conversation && !messages.is_empty() {
-        let logs_dir = data_dir.join("logs");
-        if !logs_dir.exists() {
-            tokio::fs::create_dir_all(&logs_dir).await?;
-        }
+    if config::save_config_except(
+        config_dir.display().to_string().as_str(),
+        &config,
+        &overrides,
+    )
+    .await?
+    {
+        println!("Saved config!");
+    }
 
-        let log_file = &logs_dir.join(format!(
-            "{}.json",
-            chrono::Local::now().format("%Y-%m-%d_%H-%M-%S")
-        ));
+    if config.app.save_conversation {
+        save_log(&client, &headers, &config, &data_dir, &conversation).await?;
+    }
 
-        let log_file = tokio::fs::File::create(log_file).await?;
-        let mut log_file = tokio::io::BufWriter::new(log_file);
+    Ok(())
+}
 
-        let messages_json = serde_json::to_string(&messages)?;
+// /imagine and gptcli image: generates images, and returns where they were saved with their data
+async fn imagine(
+    client: &reqwest::Client,
+    headers: &reqwest::header::HeaderMap,
+    config: &config::Config,
+    data_dir: &Path,
+    progress: &Progress,
+    interrupt: &Interrupt,
+    input: &str,
+) -> Result<Vec<(path::PathBuf, Vec<u8>)>, Box<dyn std::error::Error>> {
+    let (request, prompt) = image::parse_image_request(&config.images, input).map_err(|e| {
+        format!(
+            "{}\nUsage: /imagine [--size <size>] [--quality <quality>] [--n <count>] <prompt>",
+            e
+        )
+    })?;
 
-        log_file.write_all(messages_json.as_bytes()).await?;
-        // Ensure the data is written to disk
-        log_file.flush().await?;
+    let generation = image::generate_images(client, headers, &config.openai, &request, &prompt);
+    let Some(images) = progress.run(interrupt, generation).await else {
+        println!("Image generation cancelled");
+        return Ok(vec![]);
+    };
 
-        let mut log_file_content = String::new();
-        for message in messages {
-            let formatted_string = match message.role.as_str() {
-                "user" => format!("[{}]\n{}", "User", message.content),
-                "assistant" => format!("[{}]\n{}", "GPT", message.content),
-                _ => format!("[{}]\n{}", message.role, message.content),
-            };
+    let directory = match &config.images.directory {
+        Some(directory) => path::PathBuf::from(directory),
+        None => data_dir.join("images"),
+    };
+    let images = images?;
+    let paths = image::save_images(&directory, &request, &prompt, &images).await?;
 
-            log_file_content.push_str(&formatted_string);
-            log_file_content.push_str("\n\n");
-        }
+    Ok(paths
+        .into_iter()
+        .zip(images.into_iter().map(|image| image.data))
+        .collect())
+}
 
-        let log_file = &logs_dir.join(format!(
-            "{}.log",
-            chrono::Local::now().format("%Y-%m-%d_%H-%M-%S")
-        ));
+// /speak: reads the last answer aloud, and returns where the audio was saved
+async fn speak(
+    client: &reqwest::Client,
+    headers: &reqwest::header::HeaderMap,
+    config: &config::Config,
+    data_dir: &Path,
+    progress: &Progress,
+    interrupt: &Interrupt,
+    text: &str,
+) -> Result<Option<path::PathBuf>, Box<dyn std::error::Error>> {
+    let speech = audio::speak(client, headers, &config.openai, &config.audio, text);
+    let Some(data) = progress.run(interrupt, speech).await else {
+        return Ok(None);
+    };
 
-        let log_file = tokio::fs::File::create(log_file).await?;
-        let mut log_file = tokio::io::BufWriter::new(log_file);
+    let directory = match &config.audio.directory {
+        Some(directory) => path::PathBuf::from(directory),
+        None => data_dir.join("audio"),
+    };
+    Ok(Some(
+        audio::save_speech(&directory, &config.audio, text, &data?).await?,
+    ))
+}
 
-        log_file.write_all(log_file_content.as_bytes()).await?;
-        log_file.flush().await?;
+// gptcli index and gptcli search, with the subcommand first in args
+async fn embeddings_command(
+    client: &reqwest::Client,
+    headers: &reqwest::header::HeaderMap,
+    config: &config::Config,
+    data_dir: &Path,
+    args: &[String],
+) {
+    let done = match embeddings::Embedder::new(client, headers, &config.openai, &config.embeddings)
+    {
+        Ok(embedder) if args[0] == "index" => {
+            rag::index_command(&embedder, &config.rag, data_dir, &args[1..]).await
+        }
+        Ok(embedder) => search::search_command(&embedder, data_dir, &args[1..]).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = done {
+        println!("{}", e);
     }
+}
 
+// Saves the session's log, and adds it to the search index if there is one
+async fn save_log(
+    client: &reqwest::Client,
+    headers: &reqwest::header::HeaderMap,
+    config: &config::Config,
+    data_dir: &Path,
+    conversation: &Conversation,
+) -> Result<(), Box<dyn std::error::Error>> {
+    conversation.save_log(&data_dir.join("logs")).await?;
+
+    let indexed =
+        match embeddings::Embedder::new(client, headers, &config.openai, &config.embeddings) {
+            Ok(embedder) => search::index_logs(&embedder, data_dir).await,
+            Err(e) => Err(e),
+        };
+    if let Err(e) = indexed {
+        println!("Error updating the search index: {}", e);
+    }
     Ok(())
 }
 
-fn highlight_message(
-    message: &String,
-    ps: &syntect::parsing::SyntaxSet,
-    ts: &syntect::highlighting::ThemeSet,
-    code_re: &Regex,
-    tiny_code_re: &Regex,
-    config: &Config,
-) -> String {
-    let mut message_mut = message.clone();
-
-    for cap in code_re.captures_iter(message.as_str()) {
-        message_mut = message_mut.replace(&cap["language"], &cap["language"].grey().to_string());
-
-        let mut language = &cap["language"];
-
-        let syntax = ps.find_syntax_by_extension(language);
-        if syntax.is_none() {
-            match &cap["language"] {
-                "rust" => language = "rs",
-                "javascript" => language = "jsx",
-                "python" => language = "py",
-                "typescript" => language = "tsx",
-                "c++" => language = "cpp",
-                "c#" => language = "cs",
-                "kotlin" => language = "kt",
-                "ruby" => language = "erb",
-                "bash" => language = "sh",
-                "shell" => language = "sh",
-                "sh" => language = "sh",
-                "powershell" => language = "ps1",
-                "elixir" => language = "ex",
-                "erlang" => language = "erl",
-                "haskell" => language = "hs",
-                "webassembly" => language = "wasm",
-                "assembly" => language = "asm",
-                "markdown" => language = "md",
-                _ => {}
-            }
-        }
-
-        let syntax = ps.find_syntax_by_extension(language);
+// With /rag on, finds the documents for the last message and sends them along with it
+async fn retrieve_sources(
+    client: &reqwest::Client,
+    headers: &reqwest::header::HeaderMap,
+    config: &config::Config,
+    rag_index: Option<&(String, rag::DocIndex)>,
+    conversation: &mut Conversation,
+) -> Vec<rag::Source> {
+    conversation.set_context(None);
+    let Some((name, index)) = rag_index else {
+        return vec![];
+    };
 
-        if syntax.is_none() {
-            continue;
+    let query = conversation
+        .messages()
+        .last()
+        .map_or(String::new(), |message| message.content.text());
+    let retrieved =
+        match embeddings::Embedder::new(client, headers, &config.openai, &config.embeddings) {
+            Ok(embedder) => index.retrieve(&embedder, &query, config.rag.top_k).await,
+            Err(e) => Err(e),
+        };
+
+    match retrieved {
+        Ok(sources) if !sources.is_empty() => {
+            conversation.set_context(Some(rag::context_message(&sources)));
+            sources
         }
-
-        let syntax = syntax.unwrap();
-
-        let mut h = HighlightLines::new(syntax, &ts.themes[config.app.syntax_theme.as_str()]);
-
-        let mut code: Vec<String> = vec![];
-        for line in LinesWithEndings::from(&cap["code"]) {
-            let ranges: Vec<(HStyle, &str)> = h.highlight_line(line, &ps).unwrap();
-            let mut escaped = as_24_bit_terminal_escaped(&ranges[..], false);
-            escaped.push_str("\x1b[0m");
-            code.push(escaped);
+        Ok(_) => vec![],
+        Err(e) => {
+            println!("Error searching {}, sending without documents: {}", name, e);
+            vec![]
         }
+    }
+}
 
-        let stylized_code = code.join("\x1b[0m");
+fn show_sources(
+    rag_index: Option<&(String, rag::DocIndex)>,
+    sources: &[rag::Source],
+    conversation: &Conversation,
+) {
+    let answered = conversation
+        .messages()
+        .last()
+        .is_some_and(|message| message.role == "assistant");
+    if let (Some((_, index)), true) = (rag_index, answered && !sources.is_empty()) {
+        rag::print_sources(index, sources);
+    }
+}
 
-        message_mut = message_mut.replace(&cap["code"], &stylized_code);
+fn show_saved_images(saved: Vec<(path::PathBuf, Vec<u8>)>, inline_images: &InlineImages) {
+    for (path, data) in saved {
+        println!("Saved {}", path.display());
+        inline_images.show(&data);
     }
+}
 
-    message_mut = message_mut.replace("```", "");
+// Adds the images mentioned with @path to the attached ones, or returns false if one can't be read
+async fn attach_mentioned_images(
+    line: &str,
+    images: &mut Vec<Part>,
+    inline_images: &InlineImages,
+) -> bool {
+    let mut mentioned = vec![];
+    for path in image::mentioned_images(line) {
+        match image::load_image(path).await {
+            Ok(attachment) => {
+                inline_images.show(&attachment.data);
+                mentioned.push(attachment.part);
+            }
+            Err(e) => {
+                println!("{}, the message was not sent", e);
+                return false;
+            }
+        }
+    }
 
-    // for cap in tiny_code_re.captures_iter(message_mut.clone().as_str()) {
-    //     let bold = &cap["tinycode"].stylize().grey().italic().bold().to_string();
-    //     message_mut = message_mut.replace(format!("`{}`", &cap["tinycode"]).as_str(), bold);
-    // }
+    images.extend(mentioned);
+    true
+}
 
-    message_mut
+// Appends to the history file right away, so the entry survives a crash and is shared with other
+// sessions. An entry used before is moved to the end instead, so it's only kept once
+fn add_history_entry(
+    rl: &mut Editor<ReplHelper, FileHistory>,
+    line: &str,
+    history_path: Option<&Path>,
+) {
+    // history_ignore_dups only skips a repeat of the last entry
+    let used_before = rl.history().iter().rev().skip(1).any(|entry| entry == line);
+
+    let saved = if used_before {
+        move_history_entry(rl, line, history_path)
+    } else {
+        match rl.add_history_entry(line) {
+            Ok(true) => history_path.map_or(Ok(()), |history_path| rl.append_history(history_path)),
+            Ok(false) => Ok(()),
+            Err(e) => Err(e),
+        }
+    };
+    if let Err(e) = saved {
+        println!("Error saving history: {}", e);
+    }
 }
 
-fn get_time_suffix(elapsed: &std::time::Duration) -> &str {
-    let elapsed = elapsed.as_secs_f32();
+// Rewrites the history without the earlier copies of the entry, and with it at the end
+fn move_history_entry(
+    rl: &mut Editor<ReplHelper, FileHistory>,
+    line: &str,
+    history_path: Option<&Path>,
+) -> rustyline::Result<()> {
+    // The file also has what other sessions added since this one started
+    if let Some(history_path) = history_path.filter(|history_path| history_path.exists()) {
+        rl.clear_history()?;
+        rl.load_history(history_path)?;
+    }
 
-    if elapsed < 1.0 {
-        "ms"
-    } else if elapsed < 60.0 {
-        "s"
-    } else if elapsed < 3600.0 {
-        "m"
-    } else {
-        "h"
+    let entries = rl
+        .history()
+        .iter()
+        .filter(|entry| *entry != line)
+        .cloned()
+        .collect::<Vec<String>>();
+    rl.clear_history()?;
+    for entry in entries {
+        rl.add_history_entry(entry)?;
+    }
+    rl.add_history_entry(line)?;
+
+    match history_path {
+        Some(history_path) => rl.save_history(history_path),
+        None => Ok(()),
     }
 }
 
+// Sends the conversation and prints the answer. Returns whether one came back, errors and Ctrl-C
+// leave the message unanswered
 async fn chat_completion(
     client: &reqwest::Client,
     headers: &reqwest::header::HeaderMap,
-    messages: &mut Vec<Message>,
-    spinner_values: &Vec<&Spinner>,
+    conversation: &mut Conversation,
+    progress: &Progress,
     config: &config::Config,
-    prompt: &String,
-    ps: &syntect::parsing::SyntaxSet,
-    ts: &syntect::highlighting::ThemeSet,
-    code_re: &Regex,
-    tiny_code_re: &Regex,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let now = std::time::Instant::now();
-    execute!(stdout(), cursor::Hide).unwrap();
-
-    println!();
-
-    // Get a random spinner
-    let spinner = spinner_values.choose(&mut rand::thread_rng()).unwrap();
-    let spinner_frames = spinner.frames.clone();
-    let spinner_interval = spinner.interval;
-
-    let spinner_frame = Arc::new(Mutex::new(spinner_frames[0].clone()));
-    let spinner_frame_clone = spinner_frame.clone();
-
-    let rainbow_speed = config.app.rainbow_speed;
-    let rainbow_delay = config.app.rainbow_delay;
-
-    let rainbow_task = tokio::spawn(async move {
-        let mut i = 0;
-
-        let dots = vec![
-            ".    ", "..   ", "...  ", ".... ", ".....", " ....", "  ...", "   ..", "    .",
-            "     ",
-        ];
-
-        loop {
-            let r = (i as f32 / rainbow_speed).sin().powi(2);
-            let g = (i as f32 / rainbow_speed + 2.0 * std::f32::consts::PI / 3.0)
-                .sin()
-                .powi(2);
-            let b = (i as f32 / rainbow_speed + 4.0 * std::f32::consts::PI / 3.0)
-                .sin()
-                .powi(2);
-
-            let color_style = Color::Rgb {
-                r: (r * 255.0) as u8,
-                g: (g * 255.0) as u8,
-                b: (b * 255.0) as u8,
-            };
-
-            // Colorize the current line
-            execute!(stdout(), cursor::MoveToColumn(0)).unwrap();
-            execute!(stdout(), style::SetForegroundColor(color_style)).unwrap();
-            let elapsed = &now.elapsed();
-            let formatted_time = get_time_suffix(elapsed);
-            print!(
-                " {} {} {}{}{}{}",
-                *spinner_frame.lock().unwrap(),
-                dots[i % dots.len()].grey(),
-                "(".grey(),
-                format!("{:.2}", elapsed.as_secs_f32()).bold().dark_green(),
-                formatted_time.grey(),
-                ")".grey()
-            );
-            execute!(stdout(), Clear(ClearType::UntilNewLine)).unwrap();
-
-            tokio::time::sleep(std::time::Duration::from_millis(rainbow_delay)).await;
-
-            i = i + 1;
-        }
-    });
-
-    let spin_task = tokio::spawn(async move {
-        loop {
-            for frame in spinner_frames.iter() {
-                // Print the frame
-                //print!("{} ({:.2}ms)", frame, now.elapsed().as_secs_f32());
-                *spinner_frame_clone.lock().unwrap() = frame.clone();
-                tokio::time::sleep(std::time::Duration::from_millis(spinner_interval.into())).await;
-            }
-        }
-    });
-
-    // message_history.push(format!("{}: {}",
-    // //"",
-    // "You",
-    // line));
-    messages.push(Message {
-        role: "user".to_string(),
-        content: prompt.clone(),
-    });
-
-    let messages_json = serde_json::to_value(&messages)?;
-    let body = serde_json::json!({
+    highlighter: &Highlighter,
+    interrupt: &Interrupt,
+) -> bool {
+    let mut body = serde_json::json!({
         "model": config.openai.model,
-        "messages": messages_json,
+        "messages": conversation.request_messages(),
         "max_tokens": config.openai.max_tokens,
+        "temperature": config.openai.temperature,
+        "top_p": config.openai.top_p,
+        "n": config.openai.n,
+        "frequency_penalty": config.openai.frequency_penalty,
+        "presence_penalty": config.openai.presence_penalty,
     });
 
-    let openai_res = client
-        .post("https://api.openai.com/v1/chat/completions")
-        .headers(headers.clone())
-        .json(&body)
-        .send()
-        .await?;
+    if let Some(stop) = &config.openai.stop {
+        body["stop"] = serde_json::json!(stop);
+    }
+    if let Some(logit_bias) = &config.openai.logit_bias {
+        match serde_json::from_str(logit_bias) {
+            Ok(logit_bias) => body["logit_bias"] = logit_bias,
+            Err(e) => println!("Ignoring invalid logit_bias: {}", e),
+        }
+    }
+    if let Some(user) = &config.openai.user {
+        body["user"] = serde_json::json!(user);
+    }
+
+    let request = async {
+        let res =
+            api::post_json(client, headers, &config.openai, "chat/completions", &body).await?;
+        let chat_completion = serde_json::from_value::<ChatCompletion>(res)?;
+        if chat_completion.choices.is_empty() {
+            return Err("The response holds no answer".into());
+        }
+        Ok::<ChatCompletion, Box<dyn std::error::Error>>(chat_completion)
+    };
 
-    let chat_completion = openai_res.json::<ChatCompletion>().await?;
+    let running = progress.start();
 
-    rainbow_task.abort();
-    spin_task.abort();
+    // Dropping the request on Ctrl-C closes the connection
+    let Some(chat_completion) = interrupt.cancellable(request).await else {
+        running.cancel().await;
+        execute!(stdout(), cursor::Show).unwrap();
+        println!("Request cancelled. /retry sends the message again, /undo drops it");
+        return false;
+    };
+    let chat_completion = match chat_completion {
+        Ok(chat_completion) => chat_completion,
+        Err(e) => {
+            running.fail().await;
+            execute!(stdout(), cursor::Show).unwrap();
+            println!(
+                "{} {}. /retry sends the message again, /undo drops it",
+                "Request failed:".red().bold(),
+                e
+            );
+            return false;
+        }
+    };
 
     let choice = &chat_completion.choices[0];
     let message = &choice.message;
@@ -605,43 +1260,29 @@ async fn chat_completion(
     // //"",
     // "GPT-3",
     // message.content));
-    messages.push(Message {
-        role: "assistant".to_string(),
-        content: message.content.clone(),
-    });
-
-    assert!(rainbow_task.await.unwrap_err().is_cancelled());
-    assert!(spin_task.await.unwrap_err().is_cancelled());
-
-    execute!(stdout(), cursor::MoveToColumn(0)).unwrap();
-    execute!(stdout(), crossterm::style::SetForegroundColor(Color::Reset)).unwrap();
-
-    execute!(stdout(), Clear(ClearType::UntilNewLine)).unwrap();
+    let content = message.content.text();
+    conversation.push(Message::assistant(&content));
 
-    print!(
-        "{} {}{}{}",
-        "✓".green().bold(),
-        "(finished in ",
-        format!("{:.2}", now.elapsed().as_secs_f32())
-            .bold()
-            .dark_green(),
-        format!("{})", get_time_suffix(&now.elapsed()))
-    );
+    running.finish().await;
 
-    let pretty_string =
-        highlight_message(&message.content, &ps, &ts, &code_re, &tiny_code_re, config);
+    let pretty_string = highlighter.highlight_message(&content);
 
     println!("\n");
 
-    let terminal_width = terminal::size().unwrap().0 as usize;
+    let terminal_width = terminal::size().map_or(80, |(columns, _)| columns as usize);
 
+    let model = if chat_completion.model.is_empty() {
+        &config.openai.model
+    } else {
+        &chat_completion.model
+    };
     println!(
-        "{}: {}\n",
+        "{} {}: {}\n",
         config.app.response_prefix.clone().dark_green().bold(),
-        textwrap::wrap(&pretty_string, terminal_width)
-            .join("\n")
+        format!("({})", model).dark_grey(),
+        textwrap::wrap(&pretty_string, terminal_width).join("\n")
     );
 
     execute!(stdout(), cursor::Show).unwrap();
-    Ok(())
+    true
 }
@@ -0,0 +1,143 @@
+use std::path::Path;
+
+use crossterm::style::Stylize;
+use serde::{Deserialize, Serialize};
+
+use crate::{api, config::OpenAIConfig};
+
+// The list is fetched again when it is older than this, in seconds
+const CACHE_TTL: i64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Model {
+    pub id: String,
+    #[serde(default)]
+    pub owned_by: String,
+    // Seconds since the epoch
+    #[serde(default)]
+    pub created: i64,
+}
+
+// models.json in the data directory
+#[derive(Serialize, Deserialize)]
+struct ModelCache {
+    // The list of one server doesn't apply to another
+    base_url: String,
+    fetched: i64,
+    models: Vec<Model>,
+}
+
+fn cache_path(data_dir: &Path) -> std::path::PathBuf {
+    data_dir.join("models.json")
+}
+
+async fn read_cache(data_dir: &Path, openai: &OpenAIConfig) -> Option<ModelCache> {
+    let contents = tokio::fs::read_to_string(cache_path(data_dir)).await.ok()?;
+    serde_json::from_str::<ModelCache>(&contents)
+        .ok()
+        .filter(|cache| cache.base_url == openai.base_url)
+}
+
+// The model ids of the last fetched list, however old, for tab completion
+pub async fn cached_ids(data_dir: &Path, openai: &OpenAIConfig) -> Vec<String> {
+    read_cache(data_dir, openai)
+        .await
+        .map(|cache| cache.models.into_iter().map(|model| model.id).collect())
+        .unwrap_or_default()
+}
+
+async fn fetch_models(
+    client: &reqwest::Client,
+    headers: &reqwest::header::HeaderMap,
+    openai: &OpenAIConfig,
+) -> Result<Vec<Model>, Box<dyn std::error::Error>> {
+    let res = api::send_json(client.get(openai.url("models")).headers(headers.clone())).await?;
+
+    Ok(serde_json::from_value(res["data"].clone())
+        .map_err(|e| format!("Unexpected model list: {}", e))?)
+}
+
+// Sorted by id, from the cache unless it is stale or refresh is set
+pub async fn list_models(
+    client: &reqwest::Client,
+    headers: &reqwest::header::HeaderMap,
+    openai: &OpenAIConfig,
+    data_dir: &Path,
+    refresh: bool,
+) -> Result<Vec<Model>, Box<dyn std::error::Error>> {
+    let now = chrono::Local::now().timestamp();
+    if let Some(cache) = read_cache(data_dir, openai).await {
+        if !refresh && now - cache.fetched < CACHE_TTL {
+            return Ok(cache.models);
+        }
+    }
+
+    let mut models = fetch_models(client, headers, openai).await?;
+    models.sort_by(|a, b| a.id.cmp(&b.id));
+
+    let cache = ModelCache {
+        base_url: openai.base_url.clone(),
+        fetched: now,
+        models,
+    };
+    tokio::fs::write(cache_path(data_dir), serde_json::to_string(&cache)?).await?;
+
+    Ok(cache.models)
+}
+
+// Models whose id holds every word of the filter, ignoring case
+fn filter_models<'a>(models: &'a [Model], filter: &str) -> Vec<&'a Model> {
+    let words = filter
+        .split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<String>>();
+    models
+        .iter()
+        .filter(|model| {
+            let id = model.id.to_lowercase();
+            words.iter().all(|word| id.contains(word))
+        })
+        .collect()
+}
+
+// /models: one model per line, with the current one marked
+pub fn print_models(models: &[Model], filter: &str, current: &str) {
+    let matching = filter_models(models, filter);
+    let width = matching
+        .iter()
+        .map(|model| model.id.len())
+        .max()
+        .unwrap_or(0);
+
+    for model in &matching {
+        let created = chrono::DateTime::from_timestamp(model.created, 0)
+            .filter(|_| model.created > 0)
+            .map_or(String::new(), |created| {
+                created.format("%Y-%m-%d").to_string()
+            });
+        let line = format!(
+            "{:width$}  {:16}  {}",
+            model.id,
+            model.owned_by,
+            created,
+            width = width
+        );
+
+        if model.id == current {
+            println!("{} {}", "*".green().bold(), line.bold());
+        } else {
+            println!("  {}", line);
+        }
+    }
+
+    if filter.is_empty() {
+        println!("{} models, switch with /model <id>", models.len());
+    } else {
+        println!(
+            "{} of {} models match {}",
+            matching.len(),
+            models.len(),
+            filter
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use std::{path::Path, time::Duration};
+
+use reqwest::{Certificate, Client, Identity, NoProxy, Proxy};
+
+use crate::config::NetworkConfig;
+
+const PEM_CERTIFICATE_START: &str = "-----BEGIN CERTIFICATE-----";
+
+// Relative paths in the config are relative to the config directory
+async fn read_file(config_dir: &Path, path: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let path = config_dir.join(path);
+    tokio::fs::read(&path)
+        .await
+        .map_err(|e| format!("Error reading {}: {}", path.display(), e).into())
+}
+
+// A file may hold a bundle of several PEM certificates
+fn parse_certificates(pem: &[u8]) -> Result<Vec<Certificate>, Box<dyn std::error::Error>> {
+    let pem = String::from_utf8_lossy(pem);
+    let certificates = pem
+        .match_indices(PEM_CERTIFICATE_START)
+        .map(|(start, _)| {
+            let end = pem[start + 1..]
+                .find(PEM_CERTIFICATE_START)
+                .map_or(pem.len(), |end| start + 1 + end);
+            Certificate::from_pem(pem[start..end].as_bytes())
+        })
+        .collect::<Result<Vec<Certificate>, reqwest::Error>>()?;
+
+    if certificates.is_empty() {
+        return Err("no PEM certificates found".into());
+    }
+    Ok(certificates)
+}
+
+fn proxy(
+    proxy: Result<Proxy, reqwest::Error>,
+    no_proxy: &Option<String>,
+) -> Result<Proxy, Box<dyn std::error::Error>> {
+    Ok(proxy?.no_proxy(no_proxy.as_deref().and_then(NoProxy::from_string)))
+}
+
+// The client used for every request, set up from the [network] config
+pub async fn build_client(
+    config_dir: &Path,
+    config: &NetworkConfig,
+) -> Result<Client, Box<dyn std::error::Error>> {
+    let mut builder = Client::builder();
+
+    if config.connect_timeout > 0.0 {
+        builder = builder.connect_timeout(Duration::from_secs_f64(config.connect_timeout));
+    }
+    if config.read_timeout > 0.0 {
+        builder = builder.timeout(Duration::from_secs_f64(config.read_timeout));
+    }
+
+    // Without these, the HTTP_PROXY, HTTPS_PROXY and NO_PROXY environment variables are used
+    if let Some(http_proxy) = &config.http_proxy {
+        builder = builder.proxy(proxy(Proxy::http(http_proxy), &config.no_proxy)?);
+    }
+    if let Some(https_proxy) = &config.https_proxy {
+        builder = builder.proxy(proxy(Proxy::https(https_proxy), &config.no_proxy)?);
+    }
+
+    for path in &config.ca_certificates {
+        let pem = read_file(config_dir, path).await?;
+        for certificate in
+            parse_certificates(&pem).map_err(|e| format!("Error loading {}: {}", path, e))?
+        {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+
+    if let Some(client_certificate) = &config.client_certificate {
+        let certificate = read_file(config_dir, client_certificate).await?;
+        // The key may be in the same file as the certificate
+        let key = match &config.client_key {
+            Some(client_key) => read_file(config_dir, client_key).await?,
+            None => certificate.clone(),
+        };
+        let identity = Identity::from_pkcs8_pem(&certificate, &key)
+            .map_err(|e| format!("Error loading {}: {}", client_certificate, e))?;
+        builder = builder.identity(identity);
+    }
+
+    Ok(builder.build()?)
+}
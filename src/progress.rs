@@ -0,0 +1,328 @@
+use std::{
+    collections::HashMap,
+    io::{stdout, Write},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crossterm::{
+    cursor, execute,
+    style::{self, Color, StyledContent, Stylize},
+    terminal::{Clear, ClearType},
+};
+use rand::seq::SliceRandom;
+use tokio::task::JoinHandle;
+
+use crate::{config::AppConfig, interrupt::Interrupt, spinners::Spinner};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorMode {
+    Rainbow,
+    Single(Color),
+    Monochrome,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProgressStyle {
+    Full,
+    Minimal,
+}
+
+pub struct Progress {
+    // Candidates for each request: every spinner for "random", one for a named spinner, none for "none"
+    spinners: Vec<Spinner>,
+    color: ColorMode,
+    style: ProgressStyle,
+    rainbow_speed: f32,
+    rainbow_delay: u64,
+}
+
+pub struct RunningProgress {
+    now: Instant,
+    style: ProgressStyle,
+    color: ColorMode,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+// https://no-color.org, plus dumb terminals which can't render escape codes at all
+fn color_disabled() -> bool {
+    std::env::var("NO_COLOR").is_ok_and(|v| !v.is_empty()) || is_dumb_terminal()
+}
+
+fn is_dumb_terminal() -> bool {
+    std::env::var("TERM").is_ok_and(|term| term == "dumb")
+}
+
+fn parse_color(color: &str) -> Option<Color> {
+    if let Some(hex) = color.strip_prefix('#') {
+        if hex.len() != 6 {
+            return None;
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+        return Some(Color::Rgb {
+            r: channel(0)?,
+            g: channel(2)?,
+            b: channel(4)?,
+        });
+    }
+
+    Color::try_from(color).ok()
+}
+
+pub fn get_time_suffix(elapsed: &Duration) -> &str {
+    let elapsed = elapsed.as_secs_f32();
+
+    if elapsed < 1.0 {
+        "ms"
+    } else if elapsed < 60.0 {
+        "s"
+    } else if elapsed < 3600.0 {
+        "m"
+    } else {
+        "h"
+    }
+}
+
+impl Progress {
+    pub fn new(spinners: &HashMap<String, Spinner>, config: &AppConfig) -> Progress {
+        let spinners = match config.spinner.as_str() {
+            "random" => spinners.values().cloned().collect(),
+            "none" => vec![],
+            name => match spinners.get(name) {
+                Some(spinner) => vec![spinner.clone()],
+                None => {
+                    println!(
+                        "Unknown spinner {}, using a random one instead",
+                        name.bold().red()
+                    );
+                    spinners.values().cloned().collect()
+                }
+            },
+        };
+
+        let color = if color_disabled() {
+            ColorMode::Monochrome
+        } else {
+            match config.spinner_color.as_str() {
+                "rainbow" => ColorMode::Rainbow,
+                "monochrome" => ColorMode::Monochrome,
+                color => match parse_color(color) {
+                    Some(color) => ColorMode::Single(color),
+                    None => {
+                        println!(
+                            "Unknown spinner color {}, using rainbow instead",
+                            color.bold().red()
+                        );
+                        ColorMode::Rainbow
+                    }
+                },
+            }
+        };
+
+        let style = if is_dumb_terminal() {
+            ProgressStyle::Minimal
+        } else {
+            match config.progress_style.as_str() {
+                "full" => ProgressStyle::Full,
+                "minimal" => ProgressStyle::Minimal,
+                style => {
+                    println!(
+                        "Unknown progress style {}, using full instead",
+                        style.bold().red()
+                    );
+                    ProgressStyle::Full
+                }
+            }
+        };
+
+        Progress {
+            spinners,
+            color,
+            style,
+            rainbow_speed: config.rainbow_speed as f32,
+            rainbow_delay: config.rainbow_delay,
+        }
+    }
+
+    // Shows the progress while the future runs, or returns None if it was cancelled with Ctrl-C
+    pub async fn run<F: std::future::Future>(
+        &self,
+        interrupt: &Interrupt,
+        future: F,
+    ) -> Option<F::Output> {
+        let running = self.start();
+        let output = interrupt.cancellable(future).await;
+        match output {
+            Some(_) => {
+                running.finish().await;
+                println!();
+            }
+            None => running.cancel().await,
+        }
+        execute!(stdout(), cursor::Show).unwrap();
+        output
+    }
+
+    pub fn start(&self) -> RunningProgress {
+        let now = Instant::now();
+
+        let mut running = RunningProgress {
+            now,
+            style: self.style,
+            color: self.color,
+            tasks: vec![],
+        };
+
+        if self.style == ProgressStyle::Minimal {
+            println!("Waiting for response...");
+            return running;
+        }
+
+        execute!(stdout(), cursor::Hide).unwrap();
+
+        println!();
+
+        let (spinner_frames, spinner_interval) = match self.spinners.choose(&mut rand::thread_rng())
+        {
+            Some(spinner) => (spinner.frames.clone(), spinner.interval),
+            None => (vec![String::new()], 0),
+        };
+
+        let spinner_frame = Arc::new(Mutex::new(spinner_frames[0].clone()));
+        let spinner_frame_clone = spinner_frame.clone();
+
+        let rainbow_speed = self.rainbow_speed;
+        let rainbow_delay = self.rainbow_delay;
+        let color = self.color;
+
+        running.tasks.push(tokio::spawn(async move {
+            let mut i = 0;
+
+            let dots = vec![
+                ".    ", "..   ", "...  ", ".... ", ".....", " ....", "  ...", "   ..", "    .",
+                "     ",
+            ];
+
+            loop {
+                let color_style = match color {
+                    ColorMode::Rainbow => {
+                        let r = (i as f32 / rainbow_speed).sin().powi(2);
+                        let g = (i as f32 / rainbow_speed + 2.0 * std::f32::consts::PI / 3.0)
+                            .sin()
+                            .powi(2);
+                        let b = (i as f32 / rainbow_speed + 4.0 * std::f32::consts::PI / 3.0)
+                            .sin()
+                            .powi(2);
+
+                        Some(Color::Rgb {
+                            r: (r * 255.0) as u8,
+                            g: (g * 255.0) as u8,
+                            b: (b * 255.0) as u8,
+                        })
+                    }
+                    ColorMode::Single(color) => Some(color),
+                    ColorMode::Monochrome => None,
+                };
+
+                // Colorize the current line
+                execute!(stdout(), cursor::MoveToColumn(0)).unwrap();
+                if let Some(color_style) = color_style {
+                    execute!(stdout(), style::SetForegroundColor(color_style)).unwrap();
+                }
+                let elapsed = &now.elapsed();
+                let formatted_time = get_time_suffix(elapsed);
+                if color == ColorMode::Monochrome {
+                    print!(
+                        " {} {} ({:.2}{})",
+                        *spinner_frame.lock().unwrap(),
+                        dots[i % dots.len()],
+                        elapsed.as_secs_f32(),
+                        formatted_time
+                    );
+                } else {
+                    print!(
+                        " {} {} {}{}{}{}",
+                        *spinner_frame.lock().unwrap(),
+                        dots[i % dots.len()].grey(),
+                        "(".grey(),
+                        format!("{:.2}", elapsed.as_secs_f32()).bold().dark_green(),
+                        formatted_time.grey(),
+                        ")".grey()
+                    );
+                }
+                execute!(stdout(), Clear(ClearType::UntilNewLine)).unwrap();
+
+                tokio::time::sleep(Duration::from_millis(rainbow_delay)).await;
+
+                i += 1;
+            }
+        }));
+
+        if spinner_frames.len() > 1 {
+            running.tasks.push(tokio::spawn(async move {
+                loop {
+                    for frame in spinner_frames.iter() {
+                        *spinner_frame_clone.lock().unwrap() = frame.clone();
+                        tokio::time::sleep(Duration::from_millis(spinner_interval.into())).await;
+                    }
+                }
+            }));
+        }
+
+        running
+    }
+}
+
+impl RunningProgress {
+    pub async fn finish(self) {
+        self.stop("✓".green().bold(), "finished in").await;
+    }
+
+    pub async fn cancel(self) {
+        self.stop("✗".red().bold(), "cancelled after").await;
+        println!();
+    }
+
+    pub async fn fail(self) {
+        self.stop("✗".red().bold(), "failed after").await;
+        println!();
+    }
+
+    async fn stop(self, mark: StyledContent<&str>, outcome: &str) {
+        for task in &self.tasks {
+            task.abort();
+        }
+
+        for task in self.tasks {
+            assert!(task.await.unwrap_err().is_cancelled());
+        }
+
+        let elapsed = self.now.elapsed();
+
+        if self.style == ProgressStyle::Full {
+            execute!(stdout(), cursor::MoveToColumn(0)).unwrap();
+            execute!(stdout(), style::SetForegroundColor(Color::Reset)).unwrap();
+            execute!(stdout(), Clear(ClearType::UntilNewLine)).unwrap();
+        }
+
+        if self.color == ColorMode::Monochrome {
+            print!(
+                "{} ({} {:.2}{})",
+                mark.content(),
+                outcome,
+                elapsed.as_secs_f32(),
+                get_time_suffix(&elapsed)
+            );
+        } else {
+            print!(
+                "{} ({} {}{})",
+                mark,
+                outcome,
+                format!("{:.2}", elapsed.as_secs_f32()).bold().dark_green(),
+                get_time_suffix(&elapsed)
+            );
+        }
+
+        stdout().flush().unwrap();
+    }
+}
@@ -0,0 +1,396 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use crossterm::style::Stylize;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::RagConfig,
+    conversation::Message,
+    embeddings::{self, Embedder},
+};
+
+// Markdown, text and source files
+const EXTENSIONS: &[&str] = &[
+    "md", "markdown", "txt", "rst", "adoc", "org", "rs", "py", "js", "jsx", "ts", "tsx", "go",
+    "java", "kt", "scala", "c", "h", "cc", "cpp", "hpp", "cs", "rb", "php", "swift", "lua", "sh",
+    "sql", "html", "css", "toml", "yaml", "yml", "json",
+];
+// Build output and dependencies, which are rarely what the question is about
+const SKIPPED_DIRS: &[&str] = &["target", "node_modules", "vendor", "dist", "build"];
+// Larger files are most likely generated
+const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+#[derive(Serialize, Deserialize)]
+pub struct DocIndex {
+    embedder: String,
+    pub root: PathBuf,
+    // By path relative to the root
+    files: BTreeMap<String, DocFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DocFile {
+    modified: u64,
+    chunks: Vec<DocChunk>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DocChunk {
+    // Line numbers, from 1
+    start: usize,
+    end: usize,
+    text: String,
+    vector: Vec<f32>,
+}
+
+// A chunk retrieved for a message, cited as path:start-end
+pub struct Source {
+    pub path: String,
+    pub start: usize,
+    pub end: usize,
+    pub score: f32,
+    text: String,
+}
+
+fn indexes_dir(data_dir: &Path) -> PathBuf {
+    data_dir.join("indexes")
+}
+
+fn index_path(data_dir: &Path, name: &str) -> PathBuf {
+    indexes_dir(data_dir).join(format!("{}.json", name))
+}
+
+pub fn index_names(data_dir: &Path) -> Vec<String> {
+    let mut names = std::fs::read_dir(indexes_dir(data_dir))
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok()?.path().file_stem()?.to_str().map(str::to_string))
+                .collect::<Vec<String>>()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names
+}
+
+// The files to index under dir, skipping hidden files and build output
+fn document_paths(dir: &Path, paths: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    let mut entries =
+        std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<std::fs::DirEntry>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if !SKIPPED_DIRS.contains(&name.as_str()) {
+                document_paths(&path, paths)?;
+            }
+        } else if file_type.is_file()
+            && entry.metadata()?.len() <= MAX_FILE_SIZE
+            && path
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .is_some_and(|extension| EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+        {
+            paths.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+// Chunks of whole lines of at most max_chars, ending at blank lines where possible
+fn chunk_lines(text: &str, max_chars: usize) -> Vec<(usize, usize, String)> {
+    let mut chunks = vec![];
+    let mut chunk = String::new();
+    let mut start = 1;
+
+    for (i, line) in text.lines().enumerate() {
+        let line_number = i + 1;
+        // Long lines, like minified code, are cut short
+        let line = match line.char_indices().nth(max_chars) {
+            Some((end, _)) => &line[..end],
+            None => line,
+        };
+
+        if !chunk.is_empty() && chunk.chars().count() + line.chars().count() + 1 > max_chars {
+            chunks.push((start, line_number - 1, std::mem::take(&mut chunk)));
+        }
+        if chunk.is_empty() {
+            start = line_number;
+        }
+        chunk.push_str(line);
+        chunk.push('\n');
+
+        // A paragraph break past half of a chunk is a good place to stop
+        if line.trim().is_empty() && chunk.chars().count() > max_chars / 2 {
+            chunks.push((start, line_number, std::mem::take(&mut chunk)));
+        }
+    }
+
+    if !chunk.is_empty() {
+        chunks.push((start, text.lines().count(), chunk));
+    }
+    chunks.retain(|(_, _, chunk)| !chunk.trim().is_empty());
+    chunks
+}
+
+// gptcli index <dir> [--name <name>]: embeds the documents in dir, again only for those which changed
+pub async fn index_command(
+    embedder: &Embedder<'_>,
+    config: &RagConfig,
+    data_dir: &Path,
+    args: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let usage = "Usage: gptcli index <dir> [--name <name>]";
+    let (dir, name) = match args {
+        [dir] => (dir, None),
+        [dir, option, name] if option == "--name" => (dir, Some(name.clone())),
+        _ => return Err(usage.into()),
+    };
+
+    let root = std::fs::canonicalize(dir).map_err(|e| format!("Error reading {}: {}", dir, e))?;
+    let name = match name {
+        Some(name) => name,
+        None => root.file_name().map_or("root".to_string(), |name| {
+            name.to_string_lossy().to_string()
+        }),
+    };
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        return Err(format!("Invalid index name {}", name).into());
+    }
+
+    let path = index_path(data_dir, &name);
+    let mut index = match tokio::fs::read_to_string(&path).await {
+        Ok(contents) => serde_json::from_str::<DocIndex>(&contents)
+            .ok()
+            .filter(|index| index.root == root && index.embedder == embedder.id()),
+        Err(_) => None,
+    }
+    .unwrap_or_else(|| DocIndex {
+        embedder: embedder.id(),
+        root: root.clone(),
+        files: BTreeMap::new(),
+    });
+
+    let mut paths = vec![];
+    document_paths(&root, &mut paths)?;
+    let files = paths
+        .into_iter()
+        .filter_map(|path| {
+            let relative = path.strip_prefix(&root).ok()?.to_string_lossy().to_string();
+            Some((relative, path))
+        })
+        .collect::<BTreeMap<String, PathBuf>>();
+    index
+        .files
+        .retain(|relative, _| files.contains_key(relative));
+
+    let mut changed = 0;
+    let mut result = Ok(());
+    for (relative, path) in &files {
+        let modified = embeddings::modified(path)?;
+        if index
+            .files
+            .get(relative)
+            .is_some_and(|file| file.modified == modified)
+        {
+            continue;
+        }
+
+        // Binary files with a text extension are skipped
+        let Ok(text) = tokio::fs::read_to_string(path).await else {
+            continue;
+        };
+        let chunks = chunk_lines(&text, config.chunk_size);
+        println!("{} ({} chunks)", relative, chunks.len());
+
+        let texts = chunks
+            .iter()
+            .map(|(_, _, text)| format!("{}\n\n{}", relative, text))
+            .collect::<Vec<String>>();
+        let vectors = match embedder.embed(&texts).await {
+            Ok(vectors) => vectors,
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        };
+
+        let chunks = chunks
+            .into_iter()
+            .zip(vectors)
+            .map(|((start, end, text), vector)| DocChunk {
+                start,
+                end,
+                text,
+                vector,
+            })
+            .collect();
+        index
+            .files
+            .insert(relative.clone(), DocFile { modified, chunks });
+        changed += 1;
+    }
+
+    // What was embedded before an error is kept, so running it again continues from there
+    tokio::fs::create_dir_all(indexes_dir(data_dir)).await?;
+    tokio::fs::write(&path, serde_json::to_string(&index)?).await?;
+    result?;
+
+    println!(
+        "Indexed {} files from {} as {} ({} new or changed). Use it with /rag on {}",
+        index.files.len(),
+        root.display(),
+        name.clone().bold(),
+        changed,
+        name
+    );
+    Ok(())
+}
+
+pub async fn load_index(
+    data_dir: &Path,
+    name: &str,
+    embedder: &Embedder<'_>,
+) -> Result<DocIndex, Box<dyn std::error::Error>> {
+    let contents = tokio::fs::read_to_string(index_path(data_dir, name))
+        .await
+        .map_err(|_| format!("No index named {}, create it with gptcli index <dir>", name))?;
+    let index = serde_json::from_str::<DocIndex>(&contents)?;
+
+    if index.embedder != embedder.id() {
+        return Err(format!(
+            "{} was embedded with {}, but the embeddings config is now {}. Run gptcli index {} --name {} again",
+            name,
+            index.embedder,
+            embedder.id(),
+            index.root.display(),
+            name
+        )
+        .into());
+    }
+
+    Ok(index)
+}
+
+impl DocIndex {
+    pub fn chunk_count(&self) -> usize {
+        self.files.values().map(|file| file.chunks.len()).sum()
+    }
+
+    // The top_k chunks most similar to the query
+    pub async fn retrieve(
+        &self,
+        embedder: &Embedder<'_>,
+        query: &str,
+        top_k: usize,
+    ) -> Result<Vec<Source>, Box<dyn std::error::Error>> {
+        let query_vector = embedder.embed(&[query.to_string()]).await?.remove(0);
+
+        let mut sources = self
+            .files
+            .iter()
+            .flat_map(|(path, file)| file.chunks.iter().map(move |chunk| (path, chunk)))
+            .map(|(path, chunk)| Source {
+                path: path.clone(),
+                start: chunk.start,
+                end: chunk.end,
+                score: embeddings::similarity(&query_vector, &chunk.vector),
+                text: chunk.text.clone(),
+            })
+            .collect::<Vec<Source>>();
+        sources.retain(|source| source.score > 0.0);
+        sources.sort_by(|a, b| b.score.total_cmp(&a.score));
+        sources.truncate(top_k);
+
+        Ok(sources)
+    }
+}
+
+// Sent before the message the sources were retrieved for, numbered so the answer can cite them
+pub fn context_message(sources: &[Source]) -> Message {
+    let mut context = "Answer the next message using these excerpts from the user's documents where they are relevant, and cite the ones you use by their number, like [1].".to_string();
+    for (i, source) in sources.iter().enumerate() {
+        context.push_str(&format!(
+            "\n\n[{}] {} (lines {}-{})\n```\n{}```",
+            i + 1,
+            source.path,
+            source.start,
+            source.end,
+            source.text
+        ));
+    }
+    Message::system(&context)
+}
+
+// Shown under the answer
+pub fn print_sources(index: &DocIndex, sources: &[Source]) {
+    println!("{}", "Sources:".dark_grey());
+    for (i, source) in sources.iter().enumerate() {
+        println!(
+            "{}",
+            format!(
+                "  [{}] {}:{}-{} ({:.2})",
+                i + 1,
+                index.root.join(&source.path).display(),
+                source.start,
+                source.end,
+                source.score
+            )
+            .dark_grey()
+        );
+    }
+    println!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_lines_keeps_small_texts_whole() {
+        assert_eq!(
+            chunk_lines("a\nb\nc\n", 100),
+            [(1, 3, "a\nb\nc\n".to_string())]
+        );
+    }
+
+    #[test]
+    fn chunk_lines_gives_the_line_range_of_each_chunk() {
+        assert_eq!(
+            chunk_lines("aaa\nbbb\nccc\n", 8),
+            [
+                (1, 2, "aaa\nbbb\n".to_string()),
+                (3, 3, "ccc\n".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn chunk_lines_stops_at_blank_lines_past_half_a_chunk() {
+        assert_eq!(
+            chunk_lines("aaa\n\nbbb\n", 8),
+            [(1, 2, "aaa\n\n".to_string()), (3, 3, "bbb\n".to_string())]
+        );
+        // Too early in the chunk to stop
+        assert_eq!(chunk_lines("a\n\nb\n", 8), [(1, 3, "a\n\nb\n".to_string())]);
+    }
+
+    #[test]
+    fn chunk_lines_cuts_long_lines_between_characters() {
+        assert_eq!(
+            chunk_lines("ééééé\nb", 3),
+            [(1, 1, "ééé\n".to_string()), (2, 2, "b\n".to_string())]
+        );
+        assert_eq!(chunk_lines("😀😀😀", 1), [(1, 1, "😀\n".to_string())]);
+    }
+}
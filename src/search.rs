@@ -0,0 +1,326 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use crossterm::style::Stylize;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    conversation,
+    embeddings::{self, Embedder},
+};
+
+// Messages longer than this are embedded in several chunks
+const CHUNK_SIZE: usize = 1500;
+// How many matching chunks are shown per session
+const SNIPPETS: usize = 2;
+
+// The embedded messages of every session log, kept in the data directory
+#[derive(Serialize, Deserialize, Default)]
+struct SearchIndex {
+    embedder: String,
+    // By log name, such as 2024-05-01_12-00-00
+    sessions: BTreeMap<String, Session>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Session {
+    // Milliseconds since the epoch, a changed log is embedded again
+    modified: u64,
+    chunks: Vec<Chunk>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Chunk {
+    role: String,
+    text: String,
+    vector: Vec<f32>,
+}
+
+// The logs to index by name, the .json file of a session or the .log file of an old one without it
+fn session_logs(logs_dir: &Path) -> Result<BTreeMap<String, PathBuf>, Box<dyn std::error::Error>> {
+    let mut logs = BTreeMap::new();
+    if !logs_dir.exists() {
+        return Ok(logs);
+    }
+
+    for entry in std::fs::read_dir(logs_dir)? {
+        let path = entry?.path();
+        let (Some(name), Some(extension)) = (
+            path.file_stem().and_then(|name| name.to_str()),
+            path.extension().and_then(|extension| extension.to_str()),
+        ) else {
+            continue;
+        };
+
+        match extension {
+            "json" => {
+                logs.insert(name.to_string(), path.clone());
+            }
+            "log" if !path.with_extension("json").exists() => {
+                logs.insert(name.to_string(), path.clone());
+            }
+            _ => {}
+        }
+    }
+
+    Ok(logs)
+}
+
+// The (role, text) chunks of a session log
+async fn log_chunks(path: &Path) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+    let contents = tokio::fs::read_to_string(path).await?;
+
+    let messages = if path
+        .extension()
+        .is_some_and(|extension| extension == "json")
+    {
+        conversation::saved_messages(&contents)?
+            .into_iter()
+            .map(|message| (message.role, message.content.text()))
+            .collect()
+    } else {
+        vec![("log".to_string(), contents)]
+    };
+
+    Ok(messages
+        .into_iter()
+        .flat_map(|(role, text)| {
+            embeddings::chunk_text(&text, CHUNK_SIZE)
+                .into_iter()
+                .map(move |chunk| (role.clone(), chunk))
+        })
+        .collect())
+}
+
+async fn load_index(path: &Path) -> SearchIndex {
+    match tokio::fs::read_to_string(path).await {
+        Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+            println!("Rebuilding the search index, it could not be read: {}", e);
+            SearchIndex::default()
+        }),
+        Err(_) => SearchIndex::default(),
+    }
+}
+
+// Embeds the new and changed logs and drops the deleted ones
+async fn update_index(
+    index: &mut SearchIndex,
+    embedder: &Embedder<'_>,
+    logs_dir: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if index.embedder != embedder.id() {
+        index.sessions.clear();
+        index.embedder = embedder.id();
+    }
+
+    let logs = session_logs(logs_dir)?;
+    index.sessions.retain(|name, _| logs.contains_key(name));
+
+    let mut changed = vec![];
+    for (name, path) in logs {
+        let modified = embeddings::modified(&path)?;
+        if index
+            .sessions
+            .get(&name)
+            .is_none_or(|session| session.modified != modified)
+        {
+            changed.push((name, path, modified));
+        }
+    }
+
+    if changed.is_empty() {
+        return Ok(());
+    }
+    println!("Indexing {} new or changed session(s)...", changed.len());
+
+    for (name, path, modified) in changed {
+        let chunks = match log_chunks(&path).await {
+            Ok(chunks) => chunks,
+            Err(e) => {
+                println!("Skipping {}: {}", path.display(), e);
+                continue;
+            }
+        };
+
+        let texts = chunks
+            .iter()
+            .map(|(_, text)| text.clone())
+            .collect::<Vec<String>>();
+        let vectors = embedder.embed(&texts).await?;
+
+        let chunks = chunks
+            .into_iter()
+            .zip(vectors)
+            .map(|((role, text), vector)| Chunk { role, text, vector })
+            .collect();
+        index.sessions.insert(name, Session { modified, chunks });
+    }
+
+    Ok(())
+}
+
+async fn updated_index(
+    embedder: &Embedder<'_>,
+    data_dir: &Path,
+) -> Result<SearchIndex, Box<dyn std::error::Error>> {
+    let index_path = data_dir.join("search_index.json");
+
+    let mut index = load_index(&index_path).await;
+    let updated = update_index(&mut index, embedder, &data_dir.join("logs")).await;
+    // Whatever was embedded before an error is kept for next time
+    tokio::fs::write(&index_path, serde_json::to_string(&index)?).await?;
+    updated?;
+
+    Ok(index)
+}
+
+// Embeds the logs saved since the index was last updated, so searches don't wait for them. Until
+// the first search there is no index, and nothing is embedded
+pub async fn index_logs(
+    embedder: &Embedder<'_>,
+    data_dir: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !data_dir.join("search_index.json").exists() {
+        return Ok(());
+    }
+    updated_index(embedder, data_dir).await?;
+    Ok(())
+}
+
+fn role_label(role: &str) -> &str {
+    match role {
+        "user" => "User",
+        "assistant" => "GPT",
+        "system" => "System",
+        _ => "Log",
+    }
+}
+
+// gptcli search [--limit <n>] <query>
+pub async fn search_command(
+    embedder: &Embedder<'_>,
+    data_dir: &Path,
+    args: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut limit = 10;
+    let mut args = args;
+    if args.first().is_some_and(|arg| arg == "--limit") {
+        limit = args
+            .get(1)
+            .and_then(|n| n.parse().ok())
+            .ok_or("--limit needs a number")?;
+        args = &args[2..];
+    }
+
+    let query = args.join(" ");
+    if query.trim().is_empty() {
+        return Err("Usage: gptcli search [--limit <n>] <query>".into());
+    }
+
+    let logs_dir = data_dir.join("logs");
+    let index = updated_index(embedder, data_dir).await?;
+
+    if index.sessions.is_empty() {
+        println!(
+            "No saved sessions in {} yet, they are saved when app.save_conversation is on",
+            logs_dir.display()
+        );
+        return Ok(());
+    }
+
+    let query_vector = embedder.embed(&[query]).await?.remove(0);
+
+    let mut results = index
+        .sessions
+        .iter()
+        .map(|(name, session)| {
+            let mut scored = session
+                .chunks
+                .iter()
+                .map(|chunk| (embeddings::similarity(&query_vector, &chunk.vector), chunk))
+                .collect::<Vec<(f32, &Chunk)>>();
+            scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+            scored.truncate(SNIPPETS);
+            (name, scored)
+        })
+        .filter(|(_, scored)| scored.first().is_some_and(|(score, _)| *score > 0.0))
+        .collect::<Vec<(&String, Vec<(f32, &Chunk)>)>>();
+    results.sort_by(|a, b| b.1[0].0.total_cmp(&a.1[0].0));
+
+    if results.is_empty() {
+        println!("No matching sessions");
+        return Ok(());
+    }
+
+    for (name, scored) in results.into_iter().take(limit) {
+        let started = chrono::NaiveDateTime::parse_from_str(name, "%Y-%m-%d_%H-%M-%S")
+            .map_or(name.clone(), |started| {
+                started.format("%Y-%m-%d %H:%M").to_string()
+            });
+        println!(
+            "{}  {:.2}  {}",
+            started.bold(),
+            scored[0].0,
+            logs_dir.join(format!("{}.log", name)).display()
+        );
+        for (_, chunk) in scored {
+            println!(
+                "  {}: {}",
+                role_label(&chunk.role).dark_green(),
+                embeddings::snippet(&chunk.text, 100)
+            );
+        }
+        println!();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        conversation::{Conversation, Message},
+        test_server::temp_dir,
+    };
+
+    async fn save_session(data_dir: &Path) {
+        let mut conversation = Conversation::new("prompt");
+        conversation.push(Message::user("fixing the deadlock"));
+        conversation.push(Message::assistant("take the locks in order"));
+        conversation.save_log(&data_dir.join("logs")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn saved_logs_are_only_indexed_once_there_is_an_index() {
+        let directory = temp_dir();
+        let data_dir = directory.path();
+        save_session(data_dir).await;
+
+        index_logs(&Embedder::Local, data_dir).await.unwrap();
+        assert!(!data_dir.join("search_index.json").exists());
+
+        // What a first search does
+        let index = updated_index(&Embedder::Local, data_dir).await.unwrap();
+        assert_eq!(index.sessions.len(), 1);
+
+        // A later session, saved the same second as the first one would overwrite it
+        tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+        save_session(data_dir).await;
+        index_logs(&Embedder::Local, data_dir).await.unwrap();
+
+        let index = load_index(&data_dir.join("search_index.json")).await;
+        assert_eq!(index.sessions.len(), 2);
+        assert_eq!(index.embedder, Embedder::Local.id());
+        let chunks = &index.sessions.values().next().unwrap().chunks;
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|chunk| chunk.text.as_str())
+                .collect::<Vec<&str>>(),
+            ["fixing the deadlock", "take the locks in order"]
+        );
+    }
+}
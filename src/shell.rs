@@ -0,0 +1,218 @@
+use std::{
+    io::{IsTerminal, Write},
+    process::Stdio,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use crossterm::style::Stylize;
+
+use crate::{
+    api, config::Config, conversation::Message, highlight::Highlighter, interrupt::Interrupt,
+    progress::Progress,
+};
+
+// Clipboard tools tried in order, before falling back to the terminal's clipboard escape
+const CLIPBOARD_COMMANDS: &[(&str, &[&str])] = &[
+    ("pbcopy", &[]),
+    ("wl-copy", &[]),
+    ("xclip", &["-selection", "clipboard"]),
+    ("xsel", &["--clipboard", "--input"]),
+    ("clip", &[]),
+];
+
+// The shell commands are written for and run in, $SHELL or sh, and cmd on Windows
+fn shell_program() -> String {
+    if cfg!(windows) {
+        "cmd".to_string()
+    } else {
+        std::env::var("SHELL")
+            .ok()
+            .filter(|shell| !shell.is_empty())
+            .unwrap_or_else(|| "sh".to_string())
+    }
+}
+
+fn shell_name() -> String {
+    let program = shell_program();
+    std::path::Path::new(&program)
+        .file_name()
+        .map_or(program.clone(), |name| name.to_string_lossy().to_string())
+}
+
+// What the command has to work on, without anything identifying like the host or user name
+fn system_context() -> String {
+    let kernel = match (sys_info::os_type(), sys_info::os_release()) {
+        (Ok(os_type), Ok(release)) => format!("{} {}", os_type, release),
+        _ => "unknown".to_string(),
+    };
+    let directory = std::env::current_dir().map_or("unknown".to_string(), |directory| {
+        directory.display().to_string()
+    });
+
+    format!(
+        "OS: {}\nKernel: {}\nArchitecture: {}\nShell: {}\nWorking directory: {}",
+        os_info::get(),
+        kernel,
+        std::env::consts::ARCH,
+        shell_name(),
+        directory
+    )
+}
+
+fn system_prompt() -> String {
+    format!(
+        "You turn a description of a task into a single {} command for this system:\n\n{}\n\nReply with the command alone in one ```{} code block, followed by one or two sentences explaining what it does and anything surprising about it. Prefer tools that are installed by default, and a single line, joining steps with pipes or && where needed. If the task can't be done safely with a command, say so instead of giving one.",
+        shell_name(),
+        system_context(),
+        shell_name()
+    )
+}
+
+// The command from the first code block, and the explanation around it
+fn parse_answer(answer: &str) -> Option<(String, String)> {
+    let start = answer.find("```")?;
+    let after_fence = &answer[start + 3..];
+    // The language tag ends at the first line break
+    let code_start = after_fence.find('\n')? + 1;
+    let code_end = after_fence[code_start..].find("```")? + code_start;
+
+    let command = after_fence[code_start..code_end].trim().to_string();
+    if command.is_empty() {
+        return None;
+    }
+    let explanation = format!(
+        "{} {}",
+        answer[..start].trim(),
+        after_fence[code_end + 3..].trim()
+    );
+
+    Some((command, explanation.trim().to_string()))
+}
+
+async fn request_command(
+    client: &reqwest::Client,
+    headers: &reqwest::header::HeaderMap,
+    config: &Config,
+    description: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    api::chat(
+        client,
+        headers,
+        &config.openai,
+        &[
+            Message::system(&system_prompt()),
+            Message::user(description),
+        ],
+        0.2,
+    )
+    .await
+}
+
+async fn run(command: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let flag = if cfg!(windows) { "/C" } else { "-c" };
+    let status = tokio::process::Command::new(shell_program())
+        .args([flag, command])
+        .status()
+        .await?;
+
+    if !status.success() {
+        println!("{}", format!("The command failed ({})", status).dark_grey());
+    }
+    Ok(())
+}
+
+// Returns where it was copied to
+async fn copy(text: &str) -> Result<&'static str, Box<dyn std::error::Error>> {
+    for (program, args) in CLIPBOARD_COMMANDS {
+        let Ok(mut child) = tokio::process::Command::new(program)
+            .args(*args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        else {
+            continue;
+        };
+
+        if let Some(mut stdin) = child.stdin.take() {
+            tokio::io::AsyncWriteExt::write_all(&mut stdin, text.as_bytes()).await?;
+        }
+        if child.wait().await?.success() {
+            return Ok(program);
+        }
+    }
+
+    // OSC 52, which most terminals support, and which works over ssh too
+    let mut stdout = std::io::stdout();
+    write!(stdout, "\x1b]52;c;{}\x07", STANDARD.encode(text))?;
+    stdout.flush()?;
+    Ok("the terminal")
+}
+
+// gptcli cmd <description>: suggests a command, and runs it only once confirmed
+pub async fn cmd_command(
+    client: &reqwest::Client,
+    headers: &reqwest::header::HeaderMap,
+    config: &Config,
+    highlighter: &Highlighter,
+    progress: &Progress,
+    interrupt: &Interrupt,
+    args: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let description = args.join(" ");
+    if description.trim().is_empty() {
+        return Err("Usage: gptcli cmd <what the command should do>".into());
+    }
+
+    let request = request_command(client, headers, config, &description);
+    let Some(answer) = progress.run(interrupt, request).await else {
+        println!("Request cancelled");
+        return Ok(());
+    };
+    let answer = answer?;
+    let Some((mut command, explanation)) = parse_answer(&answer) else {
+        // Most likely a refusal or a question back
+        println!("{}", answer);
+        return Ok(());
+    };
+
+    let language = if cfg!(windows) { "bat" } else { "sh" };
+    println!(
+        "\n{}\n",
+        highlighter
+            .highlight_message(&format!("```{}\n{}\n```", language, command))
+            .trim()
+    );
+    if !explanation.is_empty() {
+        println!("{}\n", explanation.dark_grey());
+    }
+
+    // Without a terminal to confirm in, the command is only shown
+    if !std::io::stdin().is_terminal() {
+        return Ok(());
+    }
+
+    loop {
+        // Cancel is the default, so pressing Enter by habit doesn't run the command
+        let choice = dialoguer::Select::new()
+            .items(&["Run", "Edit", "Copy", "Cancel"])
+            .default(3)
+            .interact_opt()?;
+
+        match choice {
+            Some(0) => return run(&command).await,
+            Some(1) => {
+                command = dialoguer::Input::<String>::new()
+                    .with_prompt("Command")
+                    .with_initial_text(command.clone())
+                    .interact_text()?;
+            }
+            Some(2) => {
+                let target = copy(&command).await?;
+                println!("Copied to {}", target);
+                return Ok(());
+            }
+            _ => return Ok(()),
+        }
+    }
+}
@@ -1,7 +1,10 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{collections::HashMap, path::Path};
 
-#[derive(Debug, Serialize, Deserialize)]
+// Copied from the cli-spinners submodule by build.rs
+const BUNDLED_SPINNERS: &str = include_str!(concat!(env!("OUT_DIR"), "/spinners.json"));
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Spinner {
     pub interval: u32,
     pub frames: Vec<String>,
@@ -14,12 +17,106 @@ pub struct Spinners {
     pub spinners: HashMap<String, Spinner>,
 }
 
-pub async fn get_spinners() -> Result<HashMap<String, Spinner>, Box<dyn std::error::Error>> {
-    let spinner_res = reqwest::get(
-        "https://raw.githubusercontent.com/sindresorhus/cli-spinners/master/spinners.json",
-    )
-    .await?;
-    let spinners: Spinners = spinner_res.json().await?;
+// Why the progress display can't use a spinner, which would index an empty list of frames or
+// redraw without pausing
+fn invalid_reason(spinner: &Spinner) -> Option<&'static str> {
+    if spinner.frames.is_empty() {
+        Some("it has no frames")
+    } else if spinner.interval == 0 && spinner.frames.len() > 1 {
+        Some("its interval is 0")
+    } else {
+        None
+    }
+}
+
+pub async fn load_spinners(
+    config_dir: &Path,
+) -> Result<HashMap<String, Spinner>, Box<dyn std::error::Error>> {
+    let mut spinners: Spinners = serde_json::from_str(BUNDLED_SPINNERS)?;
+
+    // spinners.json in the config dir adds to (or replaces) the bundled spinners
+    let override_path = config_dir.join("spinners.json");
+    if override_path.exists() {
+        match serde_json::from_str::<Spinners>(&tokio::fs::read_to_string(&override_path).await?) {
+            Ok(user_spinners) => {
+                for (name, spinner) in user_spinners.spinners {
+                    match invalid_reason(&spinner) {
+                        Some(reason) => println!(
+                            "Ignoring the {} spinner in {}, {}",
+                            name,
+                            override_path.display(),
+                            reason
+                        ),
+                        None => {
+                            spinners.spinners.insert(name, spinner);
+                        }
+                    }
+                }
+            }
+            Err(e) => println!("Error parsing {}: {}", override_path.display(), e),
+        }
+    }
 
     Ok(spinners.spinners)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{temp_dir, TempDir};
+
+    async fn config_dir(spinners_file: &str) -> TempDir {
+        let directory = temp_dir();
+        tokio::fs::write(directory.path().join("spinners.json"), spinners_file)
+            .await
+            .unwrap();
+        directory
+    }
+
+    #[tokio::test]
+    async fn bundled_spinners_are_valid() {
+        let spinners = load_spinners(temp_dir().path()).await.unwrap();
+
+        assert!(!spinners.is_empty());
+        for (name, spinner) in &spinners {
+            assert_eq!(invalid_reason(spinner), None, "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn user_spinners_are_added_unless_invalid() {
+        let directory = config_dir(
+            r#"{
+                "mine": {"interval": 100, "frames": ["a", "b"]},
+                "still": {"interval": 0, "frames": ["a"]},
+                "empty": {"interval": 100, "frames": []},
+                "busy": {"interval": 0, "frames": ["a", "b"]}
+            }"#,
+        )
+        .await;
+
+        let spinners = load_spinners(directory.path()).await.unwrap();
+
+        assert_eq!(spinners["mine"].frames, ["a", "b"]);
+        assert_eq!(spinners["still"].frames, ["a"]);
+        assert!(!spinners.contains_key("empty"));
+        assert!(!spinners.contains_key("busy"));
+    }
+
+    #[tokio::test]
+    async fn invalid_user_spinners_keep_the_bundled_ones() {
+        let bundled = load_spinners(temp_dir().path()).await.unwrap();
+        let Some((name, _)) = bundled.iter().next() else {
+            panic!("No bundled spinners");
+        };
+        let directory = config_dir(&format!(
+            r#"{{"{}": {{"interval": 80, "frames": []}}}}"#,
+            name
+        ))
+        .await;
+
+        let spinners = load_spinners(directory.path()).await.unwrap();
+
+        assert_eq!(spinners[name].frames, bundled[name].frames);
+    }
+}
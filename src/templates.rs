@@ -0,0 +1,256 @@
+use std::{
+    collections::HashMap,
+    io::IsTerminal,
+    path::{Path, PathBuf},
+};
+
+use crossterm::style::Stylize;
+
+// A template is text with placeholders between double braces:
+//   {{name}}          a variable, asked for when it is not given
+//   {{name|default}}  a variable with a default
+//   {{@path}}         the contents of a file
+//   {{@$name}}        the contents of the file a variable names
+enum Piece<'a> {
+    Text(&'a str),
+    Variable {
+        name: &'a str,
+        default: Option<&'a str>,
+    },
+    Include(&'a str),
+}
+
+fn templates_dir(config_dir: &Path) -> PathBuf {
+    config_dir.join("templates")
+}
+
+// Any file in the templates folder, named without its extension
+fn template_paths(config_dir: &Path) -> Vec<(String, PathBuf)> {
+    let mut templates = std::fs::read_dir(templates_dir(config_dir))
+        .map(|entries| {
+            entries
+                .filter_map(|entry| {
+                    let path = entry.ok()?.path();
+                    let name = path.file_stem()?.to_str()?.to_string();
+                    (path.is_file() && !name.starts_with('.')).then_some((name, path))
+                })
+                .collect::<Vec<(String, PathBuf)>>()
+        })
+        .unwrap_or_default();
+    templates.sort();
+    templates
+}
+
+pub fn template_names(config_dir: &Path) -> Vec<String> {
+    template_paths(config_dir)
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect()
+}
+
+fn parse(template: &str) -> Result<Vec<Piece<'_>>, Box<dyn std::error::Error>> {
+    let mut pieces = vec![];
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        pieces.push(Piece::Text(&rest[..start]));
+        let end = rest[start..].find("}}").ok_or_else(|| {
+            format!(
+                "Unclosed {{{{ in \"{}\"",
+                &rest[start..].lines().next().unwrap_or("")
+            )
+        })?;
+        let body = rest[start + 2..start + end].trim();
+
+        pieces.push(match body.strip_prefix('@') {
+            Some(path) => Piece::Include(path.trim()),
+            None => {
+                let (name, default) = match body.split_once('|') {
+                    Some((name, default)) => (name.trim(), Some(default.trim())),
+                    None => (body, None),
+                };
+                if name.is_empty() {
+                    return Err("Empty {{}} placeholder".into());
+                }
+                Piece::Variable { name, default }
+            }
+        });
+
+        rest = &rest[start + end + 2..];
+    }
+    pieces.push(Piece::Text(rest));
+
+    Ok(pieces)
+}
+
+// The variables of a template in order, with their defaults
+fn variables<'a>(pieces: &[Piece<'a>]) -> Vec<(&'a str, Option<&'a str>)> {
+    let mut variables: Vec<(&str, Option<&str>)> = vec![];
+    for piece in pieces {
+        let (name, default) = match piece {
+            Piece::Variable { name, default } => (*name, *default),
+            Piece::Include(path) => match path.strip_prefix('$') {
+                Some(name) => (name, None),
+                None => continue,
+            },
+            Piece::Text(_) => continue,
+        };
+
+        match variables.iter_mut().find(|(known, _)| *known == name) {
+            // A default given anywhere applies to every use
+            Some((_, known_default)) => *known_default = known_default.or(default),
+            None => variables.push((name, default)),
+        }
+    }
+    variables
+}
+
+// Splits on whitespace, except inside quotes, so values can hold spaces: focus="error handling"
+pub fn split_args(input: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut args = vec![];
+    let mut arg = String::new();
+    let mut in_arg = false;
+    let mut quote = None;
+
+    for c in input.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => arg.push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                in_arg = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::take(&mut arg));
+                    in_arg = false;
+                }
+            }
+            (None, c) => {
+                arg.push(c);
+                in_arg = true;
+            }
+        }
+    }
+
+    if quote.is_some() {
+        return Err("Unclosed quote".into());
+    }
+    if in_arg {
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+// Fills in the template called name, from key=value arguments, the defaults, and then by asking
+pub async fn render(
+    config_dir: &Path,
+    name: &str,
+    args: &[String],
+) -> Result<String, Box<dyn std::error::Error>> {
+    let path = template_paths(config_dir)
+        .into_iter()
+        .find(|(template, _)| template == name)
+        .map(|(_, path)| path)
+        .ok_or_else(|| {
+            format!(
+                "No template named {} in {}",
+                name,
+                templates_dir(config_dir).display()
+            )
+        })?;
+    let template = tokio::fs::read_to_string(&path)
+        .await
+        .map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+    let pieces = parse(&template).map_err(|e| format!("Error in template {}: {}", name, e))?;
+    let variables = variables(&pieces);
+
+    let mut values = HashMap::new();
+    for arg in args {
+        let (key, value) = arg
+            .split_once('=')
+            .ok_or_else(|| format!("Expected key=value, got {}", arg))?;
+        if !variables.iter().any(|(name, _)| *name == key) {
+            return Err(format!(
+                "{} has no variable {}, its variables are: {}",
+                name,
+                key,
+                variables
+                    .iter()
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            )
+            .into());
+        }
+        values.insert(key.to_string(), value.to_string());
+    }
+
+    for (name, default) in &variables {
+        if values.contains_key(*name) {
+            continue;
+        }
+        let value = match default {
+            Some(default) => default.to_string(),
+            // Asking needs a terminal to answer in
+            None if !std::io::stdin().is_terminal() => {
+                return Err(format!("Missing {}, pass it as {}=<value>", name, name).into());
+            }
+            None => dialoguer::Input::<String>::new()
+                .with_prompt(*name)
+                .interact()?,
+        };
+        values.insert(name.to_string(), value);
+    }
+
+    let mut rendered = String::new();
+    for piece in &pieces {
+        match piece {
+            Piece::Text(text) => rendered.push_str(text),
+            Piece::Variable { name, .. } => rendered.push_str(&values[*name]),
+            Piece::Include(path) => {
+                let path = match path.strip_prefix('$') {
+                    Some(name) => values[name].as_str(),
+                    None => path,
+                };
+                let contents = tokio::fs::read_to_string(path)
+                    .await
+                    .map_err(|e| format!("Error including {}: {}", path, e))?;
+                rendered.push_str(&contents);
+            }
+        }
+    }
+
+    Ok(rendered.trim().to_string())
+}
+
+// /t without a name: the templates and their variables
+pub async fn print_templates(config_dir: &Path) {
+    let templates = template_paths(config_dir);
+    if templates.is_empty() {
+        println!(
+            "No templates yet, add them as files to {}",
+            templates_dir(config_dir).display()
+        );
+        return;
+    }
+
+    for (name, path) in templates {
+        let variables = match tokio::fs::read_to_string(&path).await {
+            Ok(template) => match parse(&template) {
+                Ok(pieces) => variables(&pieces)
+                    .into_iter()
+                    .map(|(name, default)| match default {
+                        Some(default) => format!("{}={}", name, default),
+                        None => name.to_string(),
+                    })
+                    .collect::<Vec<String>>()
+                    .join(" "),
+                Err(e) => format!("(invalid: {})", e),
+            },
+            Err(e) => format!("(unreadable: {})", e),
+        };
+        println!("{} {}", name.bold(), variables.dark_grey());
+    }
+    println!("Use one with /t <name> [key=value ...]");
+}
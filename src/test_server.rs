@@ -0,0 +1,121 @@
+// A local HTTP server for tests, which answers one request with a canned response, and the
+// other fixtures tests share
+
+use std::path::{Path, PathBuf};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    task::JoinHandle,
+};
+
+use crate::config::OpenAIConfig;
+
+pub struct Request {
+    // The request line and headers
+    pub head: String,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn body_text(&self) -> String {
+        String::from_utf8_lossy(&self.body).to_string()
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).unwrap()
+    }
+}
+
+// Starts the server, and returns an OpenAI config pointed at it and the request it receives
+pub async fn serve_once(
+    status: &str,
+    content_type: &str,
+    response: &[u8],
+) -> (OpenAIConfig, JoinHandle<Request>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let openai = OpenAIConfig {
+        base_url: format!("http://{}/v1", listener.local_addr().unwrap()),
+        ..OpenAIConfig::default()
+    };
+
+    let status = status.to_string();
+    let content_type = content_type.to_string();
+    let response = response.to_vec();
+    let handle = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        let mut data = vec![];
+        let mut buffer = [0; 8192];
+        let head_end = loop {
+            let read = stream.read(&mut buffer).await.unwrap();
+            assert!(read > 0, "The connection closed before the headers ended");
+            data.extend_from_slice(&buffer[..read]);
+            if let Some(end) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+                break end;
+            }
+        };
+
+        let head = String::from_utf8_lossy(&data[..head_end]).to_string();
+        let length = head
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("content-length")
+                    .then(|| value.trim().parse::<usize>().ok())?
+            })
+            .unwrap_or(0);
+        let mut body = data[head_end + 4..].to_vec();
+        while body.len() < length {
+            let read = stream.read(&mut buffer).await.unwrap();
+            assert!(read > 0, "The connection closed before the body ended");
+            body.extend_from_slice(&buffer[..read]);
+        }
+
+        let mut reply = format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            content_type,
+            response.len()
+        )
+        .into_bytes();
+        reply.extend_from_slice(&response);
+        stream.write_all(&reply).await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        Request { head, body }
+    });
+
+    (openai, handle)
+}
+
+// The headers main sends every request with
+pub fn headers() -> reqwest::header::HeaderMap {
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.append("Content-Type", "application/json".parse().unwrap());
+    headers.append("Authorization", "Bearer test-key".parse().unwrap());
+    headers
+}
+
+// A new directory for a test's files, deleted with them when dropped
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+pub fn temp_dir() -> TempDir {
+    let path = std::env::temp_dir().join(format!("gptcli-test-{:016x}", rand::random::<u64>()));
+    std::fs::create_dir_all(&path).unwrap();
+    TempDir { path }
+}